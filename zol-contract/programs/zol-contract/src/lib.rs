@@ -1,5 +1,9 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_lang::Discriminator;
+use anchor_lang::solana_program::hash::hashv;
+use anchor_lang::solana_program::program_option::COption;
+use anchor_spl::associated_token::{get_associated_token_address, AssociatedToken};
+use anchor_spl::token::{self, Approve, Burn, CloseAccount, Mint, MintTo, Token, TokenAccount, Transfer};
 
 declare_id!("Hxmj5SzEPU4gJkbQHWaaXHEQN7SK1CKEuUFhvUf8qBAv");
 
@@ -7,39 +11,86 @@ declare_id!("Hxmj5SzEPU4gJkbQHWaaXHEQN7SK1CKEuUFhvUf8qBAv");
 pub mod zol_contract {
     use super::*;
 
-    pub fn initialize_game(ctx: Context<InitializeGame>) -> Result<()> {
+    pub fn initialize_game(ctx: Context<InitializeGame>, factions: Vec<FactionConfig>) -> Result<()> {
+        validate_faction_graph(&factions)?;
+
         let game_state = &mut ctx.accounts.game_state;
+        game_state.version = ACCOUNT_VERSION;
         game_state.epoch_number = 1;
         game_state.epoch_start_ts = Clock::get()?.unix_timestamp;
-        game_state.epoch_end_ts = game_state.epoch_start_ts + 259200; // 3 days in seconds
+        game_state.epoch_end_ts = game_state.epoch_start_ts + DEFAULT_EPOCH_DURATION;
         game_state.total_tvl = 0;
+        game_state.epoch_yield_pool = 0;
+        game_state.total_committed = 0;
+        game_state.settled_count = 0;
         game_state.status = GameStatus::Active;
+        game_state.status_before_pause = GameStatus::Active;
         game_state.admin = *ctx.accounts.admin.key;
-        
-        // Initialize Factions
-        game_state.factions = [
-            FactionState { id: 0, name: "Vanguard".to_string(), tvl: 0, score: 0 },
-            FactionState { id: 1, name: "Mage".to_string(), tvl: 0, score: 0 },
-            FactionState { id: 2, name: "Assassin".to_string(), tvl: 0, score: 0 },
-        ];
+        game_state.pending_admin = None;
+        game_state.roles = RoleAssignments::default();
 
+        ctx.accounts.game_config.set_inner(GameConfig::with_defaults());
+        
+        // Initialize Factions (id = position in the list)
+        let epoch_start_ts = game_state.epoch_start_ts;
+        game_state.factions = factions.into_iter().enumerate().map(|(id, config)| FactionState {
+            id: id as u8,
+            name: config.name,
+            prey: config.prey,
+            predators: config.predators,
+            last_accrual_ts: epoch_start_ts,
+            ..FactionState::default()
+        }).collect();
+
+        emit!(GameInitialized {
+            admin: game_state.admin,
+            faction_count: game_state.factions.len() as u8,
+            epoch_start_ts: game_state.epoch_start_ts,
+            epoch_end_ts: game_state.epoch_end_ts,
+        });
         msg!("ZOL Game Initialized. Epoch 1 Started.");
         Ok(())
     }
 
-    pub fn init_vault(_ctx: Context<InitVault>) -> Result<()> {
+    // Binds the game to its USDC mint; every token account passed later is checked against it.
+    pub fn init_vault(ctx: Context<InitVault>) -> Result<()> {
+        ctx.accounts.game_state.usdc_mint = ctx.accounts.usdc_mint.key();
+
+        emit!(VaultInitialized {
+            vault: ctx.accounts.vault.key(),
+            shop_treasury: ctx.accounts.shop_treasury.key(),
+            usdc_mint: ctx.accounts.usdc_mint.key(),
+        });
         msg!("Vault Initialized");
         Ok(())
     }
 
+    // init_vault creates the shop treasury next to the vault. Deploys whose vault predates the
+    // treasury (the baseline, see migrate_game_state) create it here, after migrating GameState.
+    pub fn init_shop_treasury(ctx: Context<InitShopTreasury>) -> Result<()> {
+        emit!(ShopTreasuryInitialized {
+            shop_treasury: ctx.accounts.shop_treasury.key(),
+            usdc_mint: ctx.accounts.usdc_mint.key(),
+        });
+        msg!("Shop Treasury Initialized");
+        Ok(())
+    }
+
+    // Active phase only, like close_position: a player joining during Settlement would count
+    // towards the players start_new_epoch waits for, and Paused / Emergency freeze the game.
     pub fn register_user(ctx: Context<RegisterUser>, faction_id: u8) -> Result<()> {
-        require!(faction_id < 3, ZolError::InvalidFaction);
+        require_status(&ctx.accounts.game_state, &[GameStatus::Active])?;
+        require!((faction_id as usize) < ctx.accounts.game_state.factions.len(), ZolError::InvalidFaction);
         
         let user_position = &mut ctx.accounts.user_position;
+        user_position.version = ACCOUNT_VERSION;
         user_position.owner = *ctx.accounts.user.key;
         user_position.faction_id = faction_id;
         user_position.deposited_amount = 0;
         user_position.last_deposit_epoch = ctx.accounts.game_state.epoch_number;
+        user_position.last_settled_epoch = 0;
+        user_position.last_switch_epoch = 0;
+        user_position.yield_forfeited_epoch = 0;
         
         // Default Automation: Compound everything (safest default)
         user_position.automation_settings = AutomationSettings {
@@ -49,15 +100,47 @@ pub mod zol_contract {
         };
 
         user_position.inventory = UserInventory::default();
+
+        let faction = &mut ctx.accounts.game_state.factions[faction_id as usize];
+        faction.player_count = faction.player_count.checked_add(1).unwrap();
         
+        emit!(UserRegistered { user: user_position.owner, faction_id });
         msg!("User Registered in Faction {}", faction_id);
         Ok(())
     }
 
+    // Unregisters an empty position and refunds its rent. Active phase only: by then every
+    // position has been settled (or skipped by the grace period), so closing and registering
+    // again cannot earn a second settlement in the same epoch.
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let user_position = &ctx.accounts.user_position;
+        let game_state = &mut ctx.accounts.game_state;
+
+        require_status(game_state, &[GameStatus::Active])?;
+        require!(
+            user_position.deposited_amount == 0 && user_position.inventory.items.is_empty(),
+            ZolError::PositionNotEmpty
+        );
+
+        let faction = &mut game_state.factions[user_position.faction_id as usize];
+        faction.player_count = faction.player_count.checked_sub(1).unwrap();
+
+        emit!(PositionClosed { user: user_position.owner, faction_id: user_position.faction_id });
+        msg!("User left Faction {}", user_position.faction_id);
+        Ok(())
+    }
+
     pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
         let user_position = &mut ctx.accounts.user_position;
         let game_state = &mut ctx.accounts.game_state;
 
+        require_status(game_state, &[GameStatus::Active])?;
+        // In commit-reveal mode allocations must stay hidden until the reveal window
+        require!(!ctx.accounts.game_config.commit_reveal_enabled, ZolError::DirectDepositDisabled);
+        // Past epoch end the scores are already fixed, so new principal would be a free ride
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < game_state.epoch_end_ts, ZolError::EpochEnded);
+
         // Transfer USDC from User to Vault
         let cpi_accounts = Transfer {
             from: ctx.accounts.user_usdc.to_account_info(),
@@ -68,13 +151,22 @@ pub mod zol_contract {
         token::transfer(cpi_ctx, amount)?;
 
         // Update State
+        accrue_user_tvl(user_position, game_state, now);
         user_position.deposited_amount = user_position.deposited_amount.checked_add(amount).unwrap();
         user_position.last_deposit_epoch = game_state.epoch_number;
         
+        let epoch_end_ts = game_state.epoch_end_ts;
         game_state.total_tvl = game_state.total_tvl.checked_add(amount).unwrap();
-        game_state.factions[user_position.faction_id as usize].tvl = 
-            game_state.factions[user_position.faction_id as usize].tvl.checked_add(amount).unwrap();
-
+        let faction = &mut game_state.factions[user_position.faction_id as usize];
+        accrue_faction_tvl(faction, now, epoch_end_ts);
+        faction.tvl = faction.tvl.checked_add(amount).unwrap();
+
+        emit!(Deposited {
+            user: user_position.owner,
+            faction_id: user_position.faction_id,
+            amount,
+            deposited_amount: user_position.deposited_amount,
+        });
         msg!("Deposited {} USDC to Faction {}", amount, user_position.faction_id);
         Ok(())
     }
@@ -83,6 +175,7 @@ pub mod zol_contract {
         let user_position = &mut ctx.accounts.user_position;
         let game_state = &mut ctx.accounts.game_state;
 
+        require_status(game_state, &[GameStatus::Active])?;
         require!(user_position.deposited_amount >= amount, ZolError::InsufficientFunds);
 
         // Transfer USDC from Vault to User
@@ -103,118 +196,411 @@ pub mod zol_contract {
         token::transfer(cpi_ctx, amount)?;
 
         // Update State
+        let now = Clock::get()?.unix_timestamp;
+        accrue_user_tvl(user_position, game_state, now);
         user_position.deposited_amount = user_position.deposited_amount.checked_sub(amount).unwrap();
         
+        let epoch_end_ts = game_state.epoch_end_ts;
         game_state.total_tvl = game_state.total_tvl.checked_sub(amount).unwrap();
-        game_state.factions[user_position.faction_id as usize].tvl = 
-            game_state.factions[user_position.faction_id as usize].tvl.checked_sub(amount).unwrap();
-
+        let faction = &mut game_state.factions[user_position.faction_id as usize];
+        accrue_faction_tvl(faction, now, epoch_end_ts);
+        faction.tvl = faction.tvl.checked_sub(amount).unwrap();
+
+        emit!(Withdrawn {
+            user: user_position.owner,
+            faction_id: user_position.faction_id,
+            amount,
+            deposited_amount: user_position.deposited_amount,
+        });
         msg!("Withdrew {} USDC", amount);
         Ok(())
     }
 
+    // Emergency exit: returns exactly the principal and closes the position.
+    // No yield, items, accrual or automation; nothing that could be broken runs here.
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        let user_position = &ctx.accounts.user_position;
+        let game_state = &mut ctx.accounts.game_state;
+
+        require!(game_state.status == GameStatus::Emergency, ZolError::InvalidGameStatus);
+
+        let amount = user_position.deposited_amount;
+        if amount > 0 {
+            transfer_from_vault(&ctx.accounts.vault, &ctx.accounts.user_usdc, &ctx.accounts.token_program, ctx.bumps.vault, amount)?;
+        }
+
+        game_state.total_tvl = game_state.total_tvl.saturating_sub(amount);
+        let faction = &mut game_state.factions[user_position.faction_id as usize];
+        faction.tvl = faction.tvl.saturating_sub(amount);
+        faction.player_count = faction.player_count.saturating_sub(1);
+
+        emit!(EmergencyWithdrawn { user: user_position.owner, amount });
+        msg!("Emergency withdrawal of {} USDC", amount);
+        Ok(())
+    }
+
+    // --- Commit-Reveal Deposits ---
+    // Phase 1 (commit): escrow USDC with hash(faction_id, amount, salt). The escrow is public,
+    // the split between factions is not. Phase 2 (reveal window before epoch end): open the
+    // commitment to credit `amount` to the faction. Unrevealed commitments count for nothing
+    // and are refunded once the epoch has ended.
+
+    pub fn commit_deposit(ctx: Context<CommitDeposit>, commitment: [u8; 32], escrow_amount: u64) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        let game_config = &ctx.accounts.game_config;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_status(game_state, &[GameStatus::Active])?;
+        require!(game_config.commit_reveal_enabled, ZolError::CommitRevealDisabled);
+        require!(escrow_amount > 0, ZolError::InsufficientFunds);
+        let reveal_start = game_state.epoch_end_ts.checked_sub(game_config.reveal_window).unwrap();
+        require!(now < reveal_start, ZolError::CommitPhaseClosed);
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_usdc.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::transfer(cpi_ctx, escrow_amount)?;
+
+        // Escrow sits in the vault but is not principal; keep it out of the yield pool
+        game_state.total_committed = game_state.total_committed.checked_add(escrow_amount).unwrap();
+
+        let deposit_commitment = &mut ctx.accounts.deposit_commitment;
+        deposit_commitment.version = ACCOUNT_VERSION;
+        deposit_commitment.owner = ctx.accounts.user.key();
+        deposit_commitment.epoch_number = game_state.epoch_number;
+        deposit_commitment.commitment = commitment;
+        deposit_commitment.escrowed_amount = escrow_amount;
+        deposit_commitment.committed_at = now;
+
+        emit!(DepositCommitted {
+            user: deposit_commitment.owner,
+            epoch_number: deposit_commitment.epoch_number,
+            escrowed_amount: escrow_amount,
+        });
+        msg!("Committed {} USDC escrow for Epoch {}", escrow_amount, game_state.epoch_number);
+        Ok(())
+    }
+
+    pub fn reveal_deposit(ctx: Context<RevealDeposit>, faction_id: u8, amount: u64, salt: [u8; 32]) -> Result<()> {
+        let user_position = &mut ctx.accounts.user_position;
+        let game_state = &mut ctx.accounts.game_state;
+        let game_config = &ctx.accounts.game_config;
+        let deposit_commitment = &ctx.accounts.deposit_commitment;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_status(game_state, &[GameStatus::Active])?;
+        require!(deposit_commitment.epoch_number == game_state.epoch_number, ZolError::RevealWindowClosed);
+        let reveal_start = game_state.epoch_end_ts.checked_sub(game_config.reveal_window).unwrap();
+        require!(now >= reveal_start, ZolError::RevealWindowNotOpen);
+        require!(now < game_state.epoch_end_ts, ZolError::RevealWindowClosed);
+
+        require!(
+            deposit_commitment_hash(faction_id, amount, &salt) == deposit_commitment.commitment,
+            ZolError::CommitmentMismatch
+        );
+        require!(amount <= deposit_commitment.escrowed_amount, ZolError::InsufficientFunds);
+        require!((faction_id as usize) < game_state.factions.len(), ZolError::InvalidFaction);
+
+        // A position can only hold one faction: an empty position follows the revealed faction,
+        // a funded one must reveal into its own. Following it is a faction switch with the same
+        // cooldown and forfeit as switch_faction, so withdraw + reveal can't route around them.
+        if faction_id != user_position.faction_id {
+            require!(user_position.deposited_amount == 0, ZolError::FactionMismatch);
+            move_to_faction(user_position, game_state, game_config, faction_id, now)?;
+        }
+
+        let escrowed_amount = deposit_commitment.escrowed_amount;
+        let epoch_end_ts = game_state.epoch_end_ts;
+
+        accrue_user_tvl(user_position, game_state, now);
+        user_position.deposited_amount = user_position.deposited_amount.checked_add(amount).unwrap();
+        user_position.last_deposit_epoch = game_state.epoch_number;
+
+        game_state.total_committed = game_state.total_committed.checked_sub(escrowed_amount).unwrap();
+        game_state.total_tvl = game_state.total_tvl.checked_add(amount).unwrap();
+        let faction = &mut game_state.factions[faction_id as usize];
+        accrue_faction_tvl(faction, now, epoch_end_ts);
+        // Weighed from the reveal only, like a plain deposit made now. Backdating to the commit
+        // would let a player commit to every faction, reveal only where it pays and refund the
+        // rest for free: a last-second snipe with full-epoch weight. Hiding is paid for in
+        // weight instead, at most reveal_window / epoch_duration of a deposit held all epoch.
+        faction.tvl = faction.tvl.checked_add(amount).unwrap();
+
+        // Return the part of the escrow that was not allocated
+        let refund = escrowed_amount.checked_sub(amount).unwrap();
+        if refund > 0 {
+            transfer_from_vault(&ctx.accounts.vault, &ctx.accounts.user_usdc, &ctx.accounts.token_program, ctx.bumps.vault, refund)?;
+        }
+
+        emit!(DepositRevealed {
+            user: user_position.owner,
+            epoch_number: game_state.epoch_number,
+            faction_id,
+            amount,
+            refunded: refund,
+        });
+        msg!("Revealed {} USDC to Faction {}", amount, faction_id);
+        Ok(())
+    }
+
+    // Returns the full escrow of a commitment that was never revealed, once its epoch has ended.
+    pub fn refund_commitment(ctx: Context<RefundCommitment>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        let deposit_commitment = &ctx.accounts.deposit_commitment;
+        let now = Clock::get()?.unix_timestamp;
+
+        let epoch_over = deposit_commitment.epoch_number < game_state.epoch_number
+            || now >= game_state.epoch_end_ts;
+        require!(epoch_over, ZolError::EpochNotEnded);
+
+        let escrowed_amount = deposit_commitment.escrowed_amount;
+        game_state.total_committed = game_state.total_committed.checked_sub(escrowed_amount).unwrap();
+        transfer_from_vault(&ctx.accounts.vault, &ctx.accounts.user_usdc, &ctx.accounts.token_program, ctx.bumps.vault, escrowed_amount)?;
+
+        emit!(CommitmentRefunded {
+            user: deposit_commitment.owner,
+            epoch_number: deposit_commitment.epoch_number,
+            amount: escrowed_amount,
+        });
+        msg!("Refunded {} USDC unrevealed commitment", escrowed_amount);
+        Ok(())
+    }
+
+    // Moves the user's whole principal to another faction. Limited by the switch cooldown,
+    // and optionally costs the current epoch's yield so switching can't be used to chase the winner.
+    pub fn switch_faction(ctx: Context<SwitchFaction>, new_faction_id: u8) -> Result<()> {
+        let user_position = &mut ctx.accounts.user_position;
+        let game_state = &mut ctx.accounts.game_state;
+        let game_config = &ctx.accounts.game_config;
+
+        require_status(game_state, &[GameStatus::Active])?;
+        require!((new_faction_id as usize) < game_state.factions.len(), ZolError::InvalidFaction);
+        require!(new_faction_id != user_position.faction_id, ZolError::InvalidFaction);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < game_state.epoch_end_ts, ZolError::EpochEnded);
+
+        let old_faction_id = user_position.faction_id;
+        move_to_faction(user_position, game_state, game_config, new_faction_id, now)?;
+        msg!("User switched from Faction {} to Faction {}", old_faction_id, new_faction_id);
+        Ok(())
+    }
+
     pub fn update_automation(
         ctx: Context<UpdateAutomation>, 
         slot_1: AutomationRule,
         slot_2: AutomationRule,
         fallback: FallbackAction
     ) -> Result<()> {
+        require_status(&ctx.accounts.game_state, &[GameStatus::Active, GameStatus::Paused])?;
+
+        // Every rule must point at a real catalog item, passed in remaining_accounts,
+        // so settlement never trips over an unknown id.
+        let catalog = load_item_catalog(ctx.remaining_accounts)?;
+        for rule in [&slot_1, &slot_2] {
+            if rule.item_id != 0 {
+                find_item(&catalog, rule.item_id)?;
+            }
+        }
+
         let user_position = &mut ctx.accounts.user_position;
         user_position.automation_settings.priority_slot_1 = slot_1;
         user_position.automation_settings.priority_slot_2 = slot_2;
         user_position.automation_settings.fallback_action = fallback;
         
+        emit!(AutomationUpdated {
+            user: user_position.owner,
+            priority_slot_1: slot_1,
+            priority_slot_2: slot_2,
+            fallback_action: fallback,
+        });
         msg!("x402 Automation Rules Updated");
         Ok(())
     }
 
-    // Call this at the end of an epoch to calculate scores
+    // Call this at the end of an epoch to calculate scores.
+    // Permissionless: any cranker can close the epoch once epoch_end_ts has passed.
     pub fn resolve_epoch(ctx: Context<ResolveEpoch>) -> Result<()> {
         let game_state = &mut ctx.accounts.game_state;
-        let _current_ts = Clock::get()?.unix_timestamp;
-
-        // In production, check if epoch_end_ts has passed. 
-        // For testing, we allow admin to force close.
-        // require!(_current_ts >= game_state.epoch_end_ts, ZolError::EpochNotEnded);
+        let current_ts = Clock::get()?.unix_timestamp;
 
-        game_state.status = GameStatus::Settlement;
+        require_status(game_state, &[GameStatus::Active])?;
+        require!(current_ts >= game_state.epoch_end_ts, ZolError::EpochNotEnded);
 
-        // Logic: Score = (% TVL Target) - (% TVL Predator)
-        // Factions: 0 (Vanguard) -> targets 2 (Assassin)
-        //           2 (Assassin) -> targets 1 (Mage)
-        //           1 (Mage)     -> targets 0 (Vanguard)
-        
-        let total_tvl = game_state.total_tvl as f64;
-        if total_tvl == 0.0 {
-            msg!("No TVL, skipping scoring.");
-            return Ok(());
-        }
-
-        let tvl_0 = game_state.factions[0].tvl as f64;
-        let tvl_1 = game_state.factions[1].tvl as f64;
-        let tvl_2 = game_state.factions[2].tvl as f64;
-
-        let pct_0 = tvl_0 / total_tvl;
-        let pct_1 = tvl_1 / total_tvl;
-        let pct_2 = tvl_2 / total_tvl;
-
-        // Vanguard (0) vs Assassin (2) [Target] - Mage (1) [Predator]
-        let score_0 = pct_2 - pct_1;
-        
-        // Mage (1) vs Vanguard (0) [Target] - Assassin (2) [Predator]
-        let score_1 = pct_0 - pct_2;
+        resolve_epoch_state(game_state, ctx.accounts.vault.amount, current_ts)?;
+        record_epoch(&mut ctx.accounts.epoch_record, game_state);
+        Ok(())
+    }
 
-        // Assassin (2) vs Mage (1) [Target] - Vanguard (0) [Predator]
-        let score_2 = pct_1 - pct_0;
+    // Emergency/testing escape hatch: the cranker (or admin) closes the epoch early.
+    // resolve_epoch and start_new_epoch stay permissionless once the epoch has ended.
+    // epoch_end_ts is pulled back to now so start_new_epoch works as usual afterwards.
+    pub fn force_close_epoch(ctx: Context<ForceCloseEpoch>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        let current_ts = Clock::get()?.unix_timestamp;
 
-        // Store scores (scaled by 10000 to keep precision in i64)
-        game_state.factions[0].score = (score_0 * 10000.0) as i64;
-        game_state.factions[1].score = (score_1 * 10000.0) as i64;
-        game_state.factions[2].score = (score_2 * 10000.0) as i64;
+        require_status(game_state, &[GameStatus::Active])?;
+        if current_ts < game_state.epoch_end_ts {
+            game_state.epoch_end_ts = current_ts;
+        }
+        msg!("Cranker force-closed Epoch {}", game_state.epoch_number);
 
-        msg!("Epoch Resolved. Scores: V:{}, M:{}, A:{}", 
-            game_state.factions[0].score, 
-            game_state.factions[1].score, 
-            game_state.factions[2].score
-        );
-        
+        resolve_epoch_state(game_state, ctx.accounts.vault.amount, current_ts)?;
+        record_epoch(&mut ctx.accounts.epoch_record, game_state);
         Ok(())
     }
 
     // The x402 Engine Core
-    pub fn execute_settlement(ctx: Context<ExecuteSettlement>, yield_amount: u64) -> Result<()> {
+    // remaining_accounts: for every catalog item, (ItemDefinition, item mint, user's token account
+    // for that mint). Items are SPL tokens, so the whole catalog is passed to read the balances.
+    pub fn execute_settlement<'info>(ctx: Context<'_, '_, '_, 'info, ExecuteSettlement<'info>>) -> Result<()> {
         let user_position = &mut ctx.accounts.user_position;
         let game_state = &mut ctx.accounts.game_state;
+        require_status(game_state, &[GameStatus::Settlement])?;
+
+        let mut items = load_settlement_items(
+            ctx.remaining_accounts,
+            &user_position.owner,
+            &ctx.accounts.item_authority.key(),
+            ctx.accounts.game_config.item_count,
+        )?;
+        require!(user_position.last_settled_epoch < game_state.epoch_number, ZolError::AlreadySettled);
+        user_position.last_settled_epoch = game_state.epoch_number;
+        game_state.settled_count = game_state.settled_count.checked_add(1).unwrap();
+
+        let epoch_record = &mut ctx.accounts.epoch_record;
+        epoch_record.settled_users = epoch_record.settled_users.checked_add(1).unwrap();
+
+        let owner = user_position.owner;
+        let epoch_number = game_state.epoch_number;
+        emit!(UserSettled { user: owner, epoch_number, faction_id: user_position.faction_id });
+
+        // Filled in as the agent goes, every exit below leaves a complete receipt
+        let receipt = &mut ctx.accounts.receipt;
+        receipt.version = ACCOUNT_VERSION;
+        receipt.owner = owner;
+        receipt.epoch_number = epoch_number;
+        receipt.faction_id = user_position.faction_id;
+        receipt.payer = ctx.accounts.payer.key();
+        receipt.settled_at = Clock::get()?.unix_timestamp;
+
+        if user_position.yield_forfeited_epoch == game_state.epoch_number {
+            receipt.yield_forfeited = true;
+            msg!("Yield forfeited this epoch (faction switch).");
+            return Ok(());
+        }
         
-        let faction_score = game_state.factions[user_position.faction_id as usize].score;
-        let mut final_yield = yield_amount;
+        accrue_user_tvl(user_position, game_state, Clock::get()?.unix_timestamp);
+        let duration = game_state.epoch_end_ts.checked_sub(game_state.epoch_start_ts).unwrap();
+        let faction = &game_state.factions[user_position.faction_id as usize];
+        let faction_score = faction.score;
+
+        // User's share of the faction allocation, pro-rata to time-weighted principal over the
+        // epoch, the same measure the faction was scored and allocated by. Capped at what is
+        // left of the pool so the epoch never pays out more than it earned.
+        let share = weighted_average(user_position.tvl_time_weighted, duration, user_position.deposited_amount)
+            .min(faction.average_tvl);
+        let pool_left = game_state.epoch_yield_pool.saturating_sub(game_state.epoch_yield_paid);
+        let mut final_yield = mul_div(faction.yield_allocation, share, faction.average_tvl).min(pool_left);
+        game_state.epoch_yield_paid = game_state.epoch_yield_paid.checked_add(final_yield).unwrap();
+        receipt.gross_yield = final_yield;
+
+        // Shield payouts and Sword bonuses are not part of the pool: the shop treasury (item
+        // revenue) moves them into the vault first, so every unit paid out is backed by tokens.
+        let treasury_available = ctx.accounts.shop_treasury.amount;
+        let treasury_bump = ctx.bumps.shop_treasury;
 
         // --- Logic A: The Buffs (Active before settlement) ---
+
+        let authority_bump = ctx.bumps.item_authority;
+        let authority_seeds = &[b"item_authority".as_ref(), &[authority_bump]];
+        let authority_signer = &[&authority_seeds[..]];
         
         if faction_score <= 0 {
             msg!("User faction lost.");
             
-            // Check Shield (Insurance)
-            if user_position.inventory.shield_count > 0 {
-                msg!("x402: Shield Activated! Burning 1 shield to protect assets.");
-                user_position.inventory.shield_count = user_position.inventory.shield_count.checked_sub(1).unwrap();
+            // Check Insurance items (e.g. Shield). The agent burns the token through the
+            // delegation the user granted to the item authority when buying it.
+            // A Shield is only consumed when the treasury can cover its payout.
+            let insurance = items.iter_mut()
+                .find(|item| {
+                    item.definition.effect == ItemEffect::Insurance
+                        && item.burnable > 0
+                        && item.definition.effect_magnitude <= treasury_available
+                });
+            if let Some(item) = insurance {
+                msg!("x402: {} Activated! Burning 1 to protect assets.", item.definition.name);
+                let cpi_accounts = Burn {
+                    mint: ctx.remaining_accounts[item.index * 3 + 1].clone(),
+                    from: ctx.remaining_accounts[item.index * 3 + 2].clone(),
+                    authority: ctx.accounts.item_authority.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    cpi_accounts,
+                    authority_signer
+                );
+                token::burn(cpi_ctx, 1)?;
+                item.balance -= 1;
+                item.burnable -= 1;
+                item.delegated -= 1;
+                receipt.shield_applied = true;
+                emit!(ShieldConsumed {
+                    user: owner,
+                    epoch_number,
+                    item_id: item.definition.id,
+                    payout: item.definition.effect_magnitude,
+                });
                 
-                // Logic: Payout "Consolation Yield" from Treasury? 
-                // Or just avoid penalties? 
-                // For this implementation, we simulate a small insurance payout coming from the Vault (funded by item sales).
-                // Let's say insurance pays flat 2 USDC (2_000_000 units) to cover gas/pain.
-                final_yield = 2_000_000; 
+                // Insurance pays the item's flat effect_magnitude to cover gas/pain,
+                // funded by item sales in the shop treasury.
+                let payout = item.definition.effect_magnitude;
+                fund_from_treasury(&ctx.accounts.shop_treasury, &ctx.accounts.vault, &ctx.accounts.token_program, treasury_bump, payout)?;
+                final_yield = payout;
             } else {
                  return Ok(()); // Total loss, no yield.
             }
         } else {
             // Winning Scenario
-            // Check Sword (Multiplier)
-            if user_position.inventory.sword_count > 0 {
-                // Boost 20%
-                let bonus = final_yield / 5;
-                final_yield = final_yield.checked_add(bonus).unwrap();
-                msg!("x402: Multiplier Sword Applied! +20% Yield Boost.");
+            // Check Yield Boost items (e.g. Sword). Boosts don't stack; the strongest one applies.
+            // Like a Shield, a Sword is burned through the item authority's delegation when it
+            // fires, so one Sword can't be passed from winner to winner within an epoch.
+            let boost = items.iter_mut()
+                .filter(|item| item.definition.effect == ItemEffect::YieldBoost && item.burnable > 0)
+                .max_by_key(|item| item.definition.effect_magnitude);
+            if let Some(item) = boost {
+                // Capped at what the treasury holds. Nothing is burned if there is no bonus to pay.
+                let bonus = mul_div(final_yield, item.definition.effect_magnitude, BPS_DENOMINATOR).min(treasury_available);
+                if bonus > 0 {
+                    let cpi_accounts = Burn {
+                        mint: ctx.remaining_accounts[item.index * 3 + 1].clone(),
+                        from: ctx.remaining_accounts[item.index * 3 + 2].clone(),
+                        authority: ctx.accounts.item_authority.to_account_info(),
+                    };
+                    let cpi_ctx = CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        cpi_accounts,
+                        authority_signer
+                    );
+                    token::burn(cpi_ctx, 1)?;
+                    item.balance -= 1;
+                    item.burnable -= 1;
+                    item.delegated -= 1;
+
+                    fund_from_treasury(&ctx.accounts.shop_treasury, &ctx.accounts.vault, &ctx.accounts.token_program, treasury_bump, bonus)?;
+                    final_yield = final_yield.checked_add(bonus).unwrap();
+                    receipt.sword_applied = true;
+                    emit!(SwordApplied {
+                        user: owner,
+                        epoch_number,
+                        item_id: item.definition.id,
+                        boost_bps: item.definition.effect_magnitude,
+                        bonus,
+                    });
+                    msg!("x402: Multiplier {} Applied! +{} bps Yield Boost.", item.definition.name, item.definition.effect_magnitude);
+                }
             }
         }
 
@@ -228,41 +614,46 @@ pub mod zol_contract {
 
         let mut remaining_yield = final_yield;
         
-        // Define Item Prices (In real app, fetch from oracle or state)
-        // 1=Sword (10 USDC), 2=Shield (2 USDC), 3=Spyglass (5 USDC)
-        let get_item_price = |id: u8| -> u64 {
-            match id {
-                1 => 10_000_000, // Sword
-                2 => 2_000_000,  // Shield
-                3 => 5_000_000,  // Spyglass
-                _ => 0,
-            }
-        };
-
         let bump = ctx.bumps.vault;
         let seeds = &[b"vault".as_ref(), &[bump]];
         let signer_seeds = &[&seeds[..]];
 
         // Helper to buy item
-        let process_rule = |rule: AutomationRule, budget: &mut u64, inventory: &mut UserInventory| -> Result<bool> {
+        let process_rule = |rule: AutomationRule, budget: &mut u64, items: &mut [SettlementItem]| -> Result<bool> {
             if rule.item_id == 0 { return Ok(false); } // No rule
+
+            let item = items.iter_mut()
+                .find(|item| item.definition.id == rule.item_id)
+                .ok_or_else(|| error!(ZolError::MissingItemDefinition))?;
+            if !item.definition.enabled { return Ok(false); } // Retired from the shop
             
             // Check Threshold
             if *budget < rule.threshold { return Ok(false); } // Not enough yield to trigger intent
 
-            let price = get_item_price(rule.item_id);
-            if price == 0 || *budget < price { return Ok(false); } // Cannot afford
+            let price = item.definition.price;
+            if *budget < price { return Ok(false); } // Cannot afford
+            if !within_item_cap(&item.definition, item.balance, 1) { return Ok(false); } // Already holding the max
+            // The crank can't approve on the user's behalf, so only buy into an account the
+            // program can still burn from (see delegate_item). Otherwise a Shield or Sword would be dead weight.
+            if item.delegated <= item.balance { return Ok(false); }
 
             // Buy Execution
             *budget = budget.checked_sub(price).unwrap();
             
-            // Update Inventory (Mint logic simulation)
-            match rule.item_id {
-                1 => inventory.sword_count = inventory.sword_count.checked_add(1).unwrap(),
-                2 => inventory.shield_count = inventory.shield_count.checked_add(1).unwrap(),
-                3 => inventory.spyglass_count = inventory.spyglass_count.checked_add(1).unwrap(),
-                _ => {},
-            }
+            // Mint the item token straight into the user's wallet
+            let cpi_accounts = MintTo {
+                mint: ctx.remaining_accounts[item.index * 3 + 1].clone(),
+                to: ctx.remaining_accounts[item.index * 3 + 2].clone(),
+                authority: ctx.accounts.item_authority.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                authority_signer
+            );
+            token::mint_to(cpi_ctx, 1)?;
+            item.balance = item.balance.checked_add(1).unwrap();
+            item.burnable = item.burnable.checked_add(1).unwrap();
 
             // Transfer Cost to Shop Treasury
             let cpi_accounts = Transfer {
@@ -277,6 +668,7 @@ pub mod zol_contract {
             );
             token::transfer(cpi_ctx, price)?;
 
+            emit!(ItemAutoPurchased { user: owner, epoch_number, item_id: rule.item_id, price });
             msg!("x402: Automated Buy - Item #{}", rule.item_id);
             Ok(true)
         };
@@ -287,10 +679,14 @@ pub mod zol_contract {
         let fallback = user_position.automation_settings.fallback_action;
 
         // Execution Step 1: Priority Slot 1
-        let _ = process_rule(slot_1, &mut remaining_yield, &mut user_position.inventory)?;
+        if process_rule(slot_1, &mut remaining_yield, &mut items)? {
+            receipt.items_bought.push(slot_1.item_id);
+        }
 
         // Execution Step 2: Priority Slot 2
-        let _ = process_rule(slot_2, &mut remaining_yield, &mut user_position.inventory)?;
+        if process_rule(slot_2, &mut remaining_yield, &mut items)? {
+            receipt.items_bought.push(slot_2.item_id);
+        }
 
 
         // --- Logic C: Fallback Settlement ---
@@ -309,13 +705,25 @@ pub mod zol_contract {
                         signer_seeds
                     );
                     token::transfer(cpi_ctx, remaining_yield)?;
+                    receipt.sent_to_wallet = remaining_yield;
+                    emit!(YieldSentToWallet { user: owner, epoch_number, amount: remaining_yield });
                     msg!("x402: Sent remaining {} USDC to Wallet", remaining_yield);
                 },
                 FallbackAction::AutoCompound => {
                      user_position.deposited_amount = user_position.deposited_amount.checked_add(remaining_yield).unwrap();
+                     receipt.compounded = remaining_yield;
                      game_state.total_tvl = game_state.total_tvl.checked_add(remaining_yield).unwrap();
-                     game_state.factions[user_position.faction_id as usize].tvl = 
-                        game_state.factions[user_position.faction_id as usize].tvl.checked_add(remaining_yield).unwrap();
+                     let now = Clock::get()?.unix_timestamp;
+                     let epoch_end_ts = game_state.epoch_end_ts;
+                     let faction = &mut game_state.factions[user_position.faction_id as usize];
+                     accrue_faction_tvl(faction, now, epoch_end_ts);
+                     faction.tvl = faction.tvl.checked_add(remaining_yield).unwrap();
+                     emit!(YieldCompounded {
+                         user: owner,
+                         epoch_number,
+                         amount: remaining_yield,
+                         deposited_amount: user_position.deposited_amount,
+                     });
                      msg!("x402: Auto-Compounded {} USDC", remaining_yield);
                 }
             }
@@ -324,222 +732,2453 @@ pub mod zol_contract {
         Ok(())
     }
 
+    // Permissionless, like resolve_epoch: only the epoch timing gates it.
+    // Waits for every registered player to be settled, or for the grace period to run out
+    // so one stuck position cannot stall the game.
     pub fn start_new_epoch(ctx: Context<StartNewEpoch>) -> Result<()> {
         let game_state = &mut ctx.accounts.game_state;
+        let game_config = &ctx.accounts.game_config;
+        let current_ts = Clock::get()?.unix_timestamp;
+
+        require_status(game_state, &[GameStatus::Settlement])?;
+        require!(current_ts >= game_state.epoch_end_ts, ZolError::EpochNotEnded);
+
+        let total_players = game_state.factions.iter()
+            .fold(0u64, |acc, f| acc.checked_add(f.player_count).unwrap());
+        let grace_over = current_ts >= game_state.epoch_end_ts.checked_add(game_config.settlement_grace_period).unwrap();
+        require!(game_state.settled_count >= total_players || grace_over, ZolError::SettlementIncomplete);
         
         // Reset scores for new epoch
         game_state.epoch_number += 1;
-        game_state.epoch_start_ts = Clock::get()?.unix_timestamp;
-        game_state.epoch_end_ts = game_state.epoch_start_ts + 259200;
+        game_state.epoch_start_ts = current_ts;
+        game_state.epoch_end_ts = game_state.epoch_start_ts.checked_add(game_config.epoch_duration).unwrap();
         game_state.status = GameStatus::Active;
+        game_state.settled_count = 0;
         
         // Reset faction scores? 
-        // TVL remains (it's sticky), but scores reset.
-        game_state.factions[0].score = 0;
-        game_state.factions[1].score = 0;
-        game_state.factions[2].score = 0;
+        // TVL remains (it's sticky), but scores and the time-weighted accumulators reset.
+        // visible_tvl is the delayed public view: frozen here until the next resolution.
+        for faction in game_state.factions.iter_mut() {
+            faction.score = 0;
+            faction.tvl_time_weighted = 0;
+            faction.last_accrual_ts = current_ts;
+            faction.visible_tvl = faction.tvl;
+        }
 
+        emit!(EpochStarted {
+            epoch_number: game_state.epoch_number,
+            epoch_start_ts: game_state.epoch_start_ts,
+            epoch_end_ts: game_state.epoch_end_ts,
+        });
         msg!("New Epoch {} Started!", game_state.epoch_number);
         Ok(())
     }
 
-    // --- Dev/Mock Tools ---
+    // Receipts of finished epochs can be closed by their owner or by whoever paid for them.
+    // The rent always goes back to the payer.
+    pub fn close_receipt(ctx: Context<CloseReceipt>) -> Result<()> {
+        let receipt = &ctx.accounts.receipt;
+        emit!(ReceiptClosed { user: receipt.owner, epoch_number: receipt.epoch_number });
+        msg!("Closed settlement receipt for Epoch {}", receipt.epoch_number);
+        Ok(())
+    }
 
-    // Simulates the Vault earning interest from an external protocol.
-    // The Admin (or a Faucet) injects "free" USDC into the vault.
-    pub fn inject_yield(ctx: Context<InjectYield>, amount: u64) -> Result<()> {
-        // Transfer USDC from Admin/Caller to Vault
+    // Manual shop purchase, paid in USDC from the user's own wallet (not from yield).
+    // Active phase only, so nobody can buy a Shield after seeing the epoch result.
+    // Items are minted to the user's associated token account.
+    pub fn buy_item(ctx: Context<BuyItem>, quantity: u64) -> Result<()> {
+        require_status(&ctx.accounts.game_state, &[GameStatus::Active])?;
+        require!(quantity > 0, ZolError::InvalidItem);
+
+        let item = &ctx.accounts.item_definition;
+
+        require!(item.enabled, ZolError::ItemNotForSale);
+        require!(within_item_cap(item, ctx.accounts.user_item_account.amount, quantity), ZolError::ItemCapReached);
+
+        let cost = item.price.checked_mul(quantity).unwrap();
         let cpi_accounts = Transfer {
-            from: ctx.accounts.provider_usdc.to_account_info(),
-            to: ctx.accounts.vault.to_account_info(),
-            authority: ctx.accounts.provider.to_account_info(),
+            from: ctx.accounts.user_usdc.to_account_info(),
+            to: ctx.accounts.shop_treasury.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
         };
         let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        token::transfer(cpi_ctx, cost)?;
+
+        mint_item(
+            &ctx.accounts.item_mint,
+            &ctx.accounts.user_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.item_authority,
+            quantity,
+        )?;
+        approve_item_authority(
+            &ctx.accounts.user_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.user,
+            &ctx.accounts.token_program,
+        )?;
+
+        emit!(ItemPurchased { user: ctx.accounts.user.key(), item_id: item.id, quantity, cost });
+        msg!("Bought {}x Item #{} for {} USDC", quantity, item.id, cost);
+        Ok(())
+    }
 
-        // Note: We do NOT update total_tvl or user deposits.
-        // This "extra" balance in the vault represents the Yield waiting to be distributed.
-        
-        msg!("Simulated Yield Injection: +{} USDC", amount);
+    // Fog of war: during an epoch the frontend shows only the delayed `visible_tvl`.
+    // Burning a Spyglass (any Intel item) writes a fresh IntelReport for the user with the live
+    // TVLs and the scores the epoch would resolve to if it ended at this slot.
+    // This is a UI convention, not secrecy: raw `tvl`, the Deposited/Withdrawn/FactionSwitched
+    // events and the token transfers themselves are all public. Only commit-reveal deposits
+    // actually hide an allocation until the reveal window.
+    pub fn use_spyglass(ctx: Context<UseSpyglass>) -> Result<()> {
+        let game_state = &ctx.accounts.game_state;
+        require_status(game_state, &[GameStatus::Active])?;
+
+        let item = &ctx.accounts.item_definition;
+        require!(item.effect == ItemEffect::Intel, ZolError::InvalidItem);
+        require!(ctx.accounts.user_item_account.amount > 0, ZolError::InsufficientItems);
+        let cpi_accounts = Burn {
+            mint: ctx.accounts.item_mint.to_account_info(),
+            from: ctx.accounts.user_item_account.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::burn(cpi_ctx, 1)?;
+
+        let clock = Clock::get()?;
+        let epoch_end_ts = game_state.epoch_end_ts;
+        let elapsed = clock.unix_timestamp.min(epoch_end_ts).checked_sub(game_state.epoch_start_ts).unwrap();
+
+        // Project resolution on copies brought up to date, without touching GameState
+        let projected: Vec<FactionState> = game_state.factions.iter().map(|faction| {
+            let mut faction = faction.clone();
+            accrue_faction_tvl(&mut faction, clock.unix_timestamp, epoch_end_ts);
+            faction.average_tvl = time_weighted_average(&faction, elapsed);
+            faction
+        }).collect();
+
+        let report = &mut ctx.accounts.intel_report;
+        report.version = ACCOUNT_VERSION;
+        report.owner = ctx.accounts.user.key();
+        report.epoch_number = game_state.epoch_number;
+        report.slot = clock.slot;
+        report.timestamp = clock.unix_timestamp;
+        report.faction_tvls = projected.iter().map(|f| f.tvl).collect();
+        report.average_tvls = projected.iter().map(|f| f.average_tvl).collect();
+        report.projected_scores = compute_scores(&projected);
+
+        emit!(SpyglassUsed { user: report.owner, epoch_number: report.epoch_number, slot: report.slot });
+        msg!("x402: Spyglass used. Intel for Epoch {} at slot {}", report.epoch_number, report.slot);
         Ok(())
     }
-}
 
-// --- Data Structures ---
+    // Converts the legacy inventory counter for one item into SPL tokens in the user's wallet.
+    // Counters are no longer read by settlement, so unmigrated items have no effect.
+    pub fn migrate_inventory(ctx: Context<MigrateInventory>) -> Result<()> {
+        let item = &ctx.accounts.item_definition;
+        let user_position = &mut ctx.accounts.user_position;
 
-#[account]
-pub struct GameState {
-    pub admin: Pubkey,
-    pub epoch_number: u64,
-    pub epoch_start_ts: i64,
-    pub epoch_end_ts: i64,
-    pub total_tvl: u64,
-    pub factions: [FactionState; 3], 
-    pub status: GameStatus,
-}
+        let count = user_position.inventory.count(item.id);
+        require!(count > 0, ZolError::InsufficientItems);
+        user_position.inventory.remove(item.id, count)?;
+
+        mint_item(
+            &ctx.accounts.item_mint,
+            &ctx.accounts.user_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.item_authority,
+            count,
+        )?;
+        approve_item_authority(
+            &ctx.accounts.user_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.user,
+            &ctx.accounts.token_program,
+        )?;
+
+        emit!(InventoryMigrated { user: user_position.owner, item_id: item.id, amount: count });
+        msg!("Migrated {}x Item #{} to SPL tokens", count, item.id);
+        Ok(())
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
-pub struct FactionState {
-    pub id: u8,
-    pub name: String, 
-    pub tvl: u64,
-    pub score: i64,
-}
+    // Creates the user's item account if needed and delegates burns to the item authority,
+    // as buying does. Settlement only auto-buys into delegated accounts, so players who never
+    // bought an item by hand call this before pointing an automation rule at it.
+    pub fn delegate_item(ctx: Context<DelegateItem>) -> Result<()> {
+        approve_item_authority(
+            &ctx.accounts.user_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.user,
+            &ctx.accounts.token_program,
+        )?;
+
+        let item_id = ctx.accounts.item_definition.id;
+        emit!(ItemDelegated { user: ctx.accounts.user.key(), item_id });
+        msg!("Delegated Item #{} burns to the program", item_id);
+        Ok(())
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
-pub enum GameStatus {
-    Active,
-    Settlement,
-    Paused,
-}
+    // --- Marketplace ---
+    // Players sell item tokens to each other. Listed tokens sit in an escrow token account
+    // owned by the Listing PDA until they are bought or the listing is cancelled.
+    // One open listing per seller and item.
 
-#[account]
-pub struct UserPosition {
-    pub owner: Pubkey,
-    pub faction_id: u8,
-    pub deposited_amount: u64,
-    pub last_deposit_epoch: u64,
-    
-    // New Advanced x402 Config
-    pub automation_settings: AutomationSettings,
-    
-    // Updated Inventory (Counters instead of bool)
-    pub inventory: UserInventory,
-}
+    pub fn list_item(ctx: Context<ListItem>, quantity: u64, price_per_item: u64) -> Result<()> {
+        require_status(&ctx.accounts.game_state, &[GameStatus::Active])?;
+        require!(quantity > 0 && price_per_item > 0, ZolError::InvalidListing);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
-pub struct AutomationSettings {
-    pub priority_slot_1: AutomationRule,
-    pub priority_slot_2: AutomationRule,
-    pub fallback_action: FallbackAction,
-}
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.seller_item_account.to_account_info(),
+            to: ctx.accounts.escrow.to_account_info(),
+            authority: ctx.accounts.seller.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::transfer(cpi_ctx, quantity)?;
+
+        let listing = &mut ctx.accounts.listing;
+        listing.version = ACCOUNT_VERSION;
+        listing.seller = ctx.accounts.seller.key();
+        listing.item_id = ctx.accounts.item_definition.id;
+        listing.quantity = quantity;
+        listing.price_per_item = price_per_item;
+        listing.bump = ctx.bumps.listing;
+
+        emit!(ItemListed { seller: listing.seller, item_id: listing.item_id, quantity, price_per_item });
+        msg!("Listed {}x Item #{} at {} USDC each", quantity, listing.item_id, price_per_item);
+        Ok(())
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
-pub struct AutomationRule {
-    pub item_id: u8, // 0=None, 1=Sword, 2=Shield, 3=Spyglass
-    pub threshold: u64, // Trigger buy if yield >= this amount
-}
+    pub fn cancel_listing(ctx: Context<CancelListing>) -> Result<()> {
+        let listing = &ctx.accounts.listing;
+        let quantity = listing.quantity;
+        let item_id = listing.item_id;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
-pub enum FallbackAction {
-    #[default]
-    AutoCompound,
-    SendToWallet,
-}
+        let seeds = &[b"listing".as_ref(), listing.seller.as_ref(), &[item_id], &[listing.bump]];
+        let signer_seeds = &[&seeds[..]];
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
-pub struct UserInventory {
-    pub sword_count: u64,    // Yield Multiplier
-    pub shield_count: u64,   // Insurance
-    pub spyglass_count: u64, // Info Reveal
-}
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.escrow.to_account_info(),
+            to: ctx.accounts.seller_item_account.to_account_info(),
+            authority: listing.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, quantity)?;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
-pub enum PayoutPreference {
-    SendToWallet,
-    AutoCompound,
-    BuyItem,
-}
+        let cpi_accounts = CloseAccount {
+            account: ctx.accounts.escrow.to_account_info(),
+            destination: ctx.accounts.seller.to_account_info(),
+            authority: listing.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer_seeds);
+        token::close_account(cpi_ctx)?;
 
-// --- Contexts ---
+        emit!(ListingCancelled { seller: listing.seller, item_id, quantity });
+        msg!("Listing for Item #{} cancelled, {} returned", item_id, quantity);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct InitializeGame<'info> {
-    #[account(
-        init, 
-        payer = admin, 
-        space = 8 + 32 + 8 + 8 + 8 + 8 + (4 + 50 + 8 + 8)*3 + 1 + 100, // Approx space calculation
-        seeds = [b"game_state"],
-        bump
-    )]
-    pub game_state: Account<'info, GameState>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+    // Buys `quantity` from a listing. The marketplace fee goes to the shop treasury,
+    // the rest to the seller. A listing that sells out is closed back to the seller.
+    // `max_price_per_item` is the price the buyer saw, so a seller can't cancel and relist
+    // higher in front of the fill.
+    pub fn fill_listing(ctx: Context<FillListing>, quantity: u64, max_price_per_item: u64) -> Result<()> {
+        require_status(&ctx.accounts.game_state, &[GameStatus::Active])?;
+        require!(quantity > 0, ZolError::InvalidListing);
+
+        let listing = &ctx.accounts.listing;
+        let item = &ctx.accounts.item_definition;
+        require!(quantity <= listing.quantity, ZolError::InsufficientItems);
+        require!(listing.price_per_item <= max_price_per_item, ZolError::PriceAboveLimit);
+        require!(within_item_cap(item, ctx.accounts.buyer_item_account.amount, quantity), ZolError::ItemCapReached);
+
+        // --- USDC: buyer -> treasury (fee) + seller ---
+        let cost = listing.price_per_item.checked_mul(quantity).unwrap();
+        let fee = mul_div(cost, ctx.accounts.game_config.marketplace_fee_bps, BPS_DENOMINATOR);
+        let seller_proceeds = cost.checked_sub(fee).unwrap();
+
+        if fee > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.buyer_usdc.to_account_info(),
+                to: ctx.accounts.shop_treasury.to_account_info(),
+                authority: ctx.accounts.buyer.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+            token::transfer(cpi_ctx, fee)?;
+        }
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.buyer_usdc.to_account_info(),
+            to: ctx.accounts.seller_usdc.to_account_info(),
+            authority: ctx.accounts.buyer.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::transfer(cpi_ctx, seller_proceeds)?;
 
-#[derive(Accounts)]
-pub struct InitVault<'info> {
-    #[account(
-        init,
-        payer = admin,
-        seeds = [b"vault"],
-        bump,
-        token::mint = usdc_mint,
-        token::authority = vault,
-    )]
-    pub vault: Account<'info, TokenAccount>,
-    pub usdc_mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        // --- Items: escrow -> buyer ---
+        let item_id = listing.item_id;
+        let seeds = &[b"listing".as_ref(), listing.seller.as_ref(), &[item_id], &[listing.bump]];
+        let signer_seeds = &[&seeds[..]];
 
-#[derive(Accounts)]
-pub struct RegisterUser<'info> {
-    #[account(
-        init, 
-        payer = user, 
-        space = 8 + 32 + 1 + 8 + 8 + (1+8+1+8+1) + (8*3) + 50, // Updated space for new structs
-        seeds = [b"user", user.key().as_ref()],
-        bump
-    )]
-    pub user_position: Account<'info, UserPosition>,
-    #[account(mut)]
-    pub game_state: Account<'info, GameState>, // Read-only unless we update player count (omitted for brevity)
-    #[account(mut)]
-    pub user: Signer<'info>,
-    pub system_program: Program<'info, System>,
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.escrow.to_account_info(),
+            to: ctx.accounts.buyer_item_account.to_account_info(),
+            authority: listing.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, quantity)?;
+
+        // Same delegation as a shop purchase, so the agent can use the bought items
+        approve_item_authority(
+            &ctx.accounts.buyer_item_account,
+            &ctx.accounts.item_authority,
+            &ctx.accounts.buyer,
+            &ctx.accounts.token_program,
+        )?;
+
+        emit!(ListingFilled {
+            seller: listing.seller,
+            buyer: ctx.accounts.buyer.key(),
+            item_id,
+            quantity,
+            cost,
+            fee,
+        });
+
+        let remaining = listing.quantity.checked_sub(quantity).unwrap();
+        if remaining == 0 {
+            let cpi_accounts = CloseAccount {
+                account: ctx.accounts.escrow.to_account_info(),
+                destination: ctx.accounts.seller.to_account_info(),
+                authority: listing.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer_seeds);
+            token::close_account(cpi_ctx)?;
+            ctx.accounts.listing.close(ctx.accounts.seller.to_account_info())?;
+        } else {
+            ctx.accounts.listing.quantity = remaining;
+        }
+
+        msg!("Bought {}x Item #{} for {} USDC ({} fee)", quantity, item_id, cost, fee);
+        Ok(())
+    }
+
+    // --- Admin Controls ---
+
+    pub fn pause_game(ctx: Context<PauseGame>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        require_status(game_state, &[GameStatus::Active, GameStatus::Settlement])?;
+
+        game_state.status_before_pause = game_state.status;
+        game_state.status = GameStatus::Paused;
+        emit!(StatusChanged { from: game_state.status_before_pause, to: GameStatus::Paused });
+
+        msg!("Game Paused");
+        Ok(())
+    }
+
+    pub fn unpause_game(ctx: Context<AdminGameControl>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        require_status(game_state, &[GameStatus::Paused])?;
+
+        // Resume whichever phase we paused from
+        game_state.status = game_state.status_before_pause;
+        emit!(StatusChanged { from: GameStatus::Paused, to: game_state.status });
+
+        msg!("Game Unpaused");
+        Ok(())
+    }
+
+    // Emergency mode is one-way: once set, the only way out for players is emergency_withdraw.
+    pub fn declare_emergency(ctx: Context<AdminGameControl>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        require!(game_state.status != GameStatus::Emergency, ZolError::InvalidGameStatus);
+        emit!(StatusChanged { from: game_state.status, to: GameStatus::Emergency });
+        game_state.status = GameStatus::Emergency;
+
+        msg!("Emergency declared by admin");
+        Ok(())
+    }
+
+    // Permissionless dead man's switch: if an epoch has gone unresolved for emergency_timeout
+    // past its end (stuck settlement logic, vanished admin), anyone can flip the game to Emergency.
+    pub fn trigger_emergency(ctx: Context<TriggerEmergency>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        let current_ts = Clock::get()?.unix_timestamp;
+
+        require!(
+            matches!(game_state.status, GameStatus::Active | GameStatus::Paused),
+            ZolError::InvalidGameStatus
+        );
+        let deadline = game_state.epoch_end_ts.checked_add(ctx.accounts.game_config.emergency_timeout).unwrap();
+        require!(current_ts >= deadline, ZolError::EmergencyTimeoutNotReached);
+        emit!(StatusChanged { from: game_state.status, to: GameStatus::Emergency });
+        game_state.status = GameStatus::Emergency;
+
+        msg!("Emergency triggered: Epoch {} unresolved since {}", game_state.epoch_number, game_state.epoch_end_ts);
+        Ok(())
+    }
+
+    // Roles let the admin hand out narrow powers (see Role). The admin keeps every role itself,
+    // so revoking a key never locks the game. One key per role; granting replaces the holder.
+    pub fn grant_role(ctx: Context<AdminGameControl>, role: Role, holder: Pubkey) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        *game_state.roles.slot_mut(role) = Some(holder);
+
+        emit!(RoleGranted { role, holder });
+        msg!("Role {:?} granted to {}", role, holder);
+        Ok(())
+    }
+
+    pub fn revoke_role(ctx: Context<AdminGameControl>, role: Role) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        require!(game_state.roles.slot_mut(role).take().is_some(), ZolError::RoleNotAssigned);
+
+        emit!(RoleRevoked { role });
+        msg!("Role {:?} revoked", role);
+        Ok(())
+    }
+
+    // Two-step admin transfer: the current admin proposes, the new key accepts by signing.
+    // Proposing again replaces the pending key.
+    pub fn propose_admin(ctx: Context<AdminGameControl>, new_admin: Pubkey) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        game_state.pending_admin = Some(new_admin);
+
+        emit!(AdminTransferProposed { admin: game_state.admin, pending_admin: new_admin });
+        msg!("Admin transfer proposed to {}", new_admin);
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        let previous_admin = game_state.admin;
+        game_state.admin = ctx.accounts.new_admin.key();
+        game_state.pending_admin = None;
+
+        emit!(AdminTransferred { previous_admin, new_admin: game_state.admin });
+
+        msg!("Admin transferred to {}", game_state.admin);
+        Ok(())
+    }
+
+    pub fn cancel_admin_transfer(ctx: Context<AdminGameControl>) -> Result<()> {
+        let game_state = &mut ctx.accounts.game_state;
+        require!(game_state.pending_admin.is_some(), ZolError::NoPendingAdmin);
+        game_state.pending_admin = None;
+
+        emit!(AdminTransferCancelled { admin: game_state.admin });
+        msg!("Admin transfer cancelled");
+        Ok(())
+    }
+
+    // Tunes the economy without a redeploy. Epoch length changes apply from the next start_new_epoch.
+    pub fn update_config(ctx: Context<UpdateConfig>, params: GameConfigParams) -> Result<()> {
+        require!(params.epoch_duration > 0, ZolError::InvalidConfig);
+        require!(params.settlement_grace_period >= 0, ZolError::InvalidConfig);
+        require!(params.reveal_window >= 0 && params.reveal_window < params.epoch_duration, ZolError::InvalidConfig);
+        require!(params.marketplace_fee_bps <= BPS_DENOMINATOR, ZolError::InvalidConfig);
+        // A short timeout would let anyone freeze the game in Emergency between epoch end and
+        // the first resolve crank, so it must outlast the settlement grace period by a margin.
+        require!(
+            params.emergency_timeout >= params.settlement_grace_period.checked_add(MIN_EMERGENCY_TIMEOUT).unwrap(),
+            ZolError::InvalidConfig
+        );
+
+        let game_config = &mut ctx.accounts.game_config;
+        game_config.epoch_duration = params.epoch_duration;
+        game_config.settlement_grace_period = params.settlement_grace_period;
+        game_config.switch_cooldown_epochs = params.switch_cooldown_epochs;
+        game_config.switch_forfeits_yield = params.switch_forfeits_yield;
+        game_config.commit_reveal_enabled = params.commit_reveal_enabled;
+        game_config.reveal_window = params.reveal_window;
+        game_config.marketplace_fee_bps = params.marketplace_fee_bps;
+        game_config.emergency_timeout = params.emergency_timeout;
+
+        emit!(ConfigUpdated { params });
+        msg!("Game Config Updated");
+        Ok(())
+    }
+
+    // --- Item Catalog ---
+
+    pub fn add_item(
+        ctx: Context<AddItem>,
+        item_id: u8,
+        name: String,
+        price: u64,
+        effect: ItemEffect,
+        effect_magnitude: u64,
+        max_per_user: u64
+    ) -> Result<()> {
+        require!(item_id != 0, ZolError::InvalidItem); // 0 means "no item" in automation rules
+        require!(ctx.accounts.game_config.item_count < MAX_ITEMS, ZolError::CatalogFull);
+        require!(!name.is_empty() && name.len() <= MAX_ITEM_NAME_LEN, ZolError::InvalidItem);
+        validate_item_economics(price, effect, effect_magnitude)?;
+
+        let item = &mut ctx.accounts.item_definition;
+        item.version = ACCOUNT_VERSION;
+        item.id = item_id;
+        item.name = name;
+        item.price = price;
+        item.effect = effect;
+        item.effect_magnitude = effect_magnitude;
+        item.max_per_user = max_per_user;
+        item.enabled = true;
+        item.mint = ctx.accounts.item_mint.key();
+
+        let game_config = &mut ctx.accounts.game_config;
+        game_config.item_count = game_config.item_count.checked_add(1).unwrap();
+
+        emit!(ItemAdded {
+            item_id: item.id,
+            mint: item.mint,
+            price: item.price,
+            effect: item.effect,
+            effect_magnitude: item.effect_magnitude,
+            max_per_user: item.max_per_user,
+        });
+        msg!("Item #{} ({}) added to the catalog", item.id, item.name);
+        Ok(())
+    }
+
+    // Items are never deleted: retiring one (enabled = false) stops sales,
+    // but copies already in inventories keep working.
+    pub fn update_item(
+        ctx: Context<UpdateItem>,
+        price: u64,
+        effect_magnitude: u64,
+        max_per_user: u64,
+        enabled: bool
+    ) -> Result<()> {
+        let item = &mut ctx.accounts.item_definition;
+        validate_item_economics(price, item.effect, effect_magnitude)?;
+
+        item.price = price;
+        item.effect_magnitude = effect_magnitude;
+        item.max_per_user = max_per_user;
+        item.enabled = enabled;
+
+        emit!(ItemUpdated {
+            item_id: item.id,
+            price: item.price,
+            effect_magnitude: item.effect_magnitude,
+            max_per_user: item.max_per_user,
+            enabled: item.enabled,
+        });
+        msg!("Item #{} updated (enabled: {})", item.id, item.enabled);
+        Ok(())
+    }
+
+    // --- Migrations ---
+    // GameState and UserPosition are the only accounts of the baseline deploy (see
+    // LegacyGameState). Every other account type was introduced already versioned, so it has
+    // no legacy form. These rebuild the two from their baseline layout in place.
+
+    // Also creates the GameConfig the baseline did not have, with default values.
+    pub fn migrate_game_state(ctx: Context<MigrateGameState>) -> Result<()> {
+        let account = ctx.accounts.game_state.to_account_info();
+        require!(account.data_len() == LEGACY_GAME_STATE_SPACE, ZolError::AlreadyMigrated);
+        let legacy: LegacyGameState = read_legacy(&account.try_borrow_data()?, GameState::DISCRIMINATOR)?;
+        require_keys_eq!(legacy.admin, ctx.accounts.admin.key(), ZolError::Unauthorized);
+
+        let game_state = legacy.upgrade(ctx.accounts.vault.mint, Clock::get()?.unix_timestamp)?;
+        write_migrated_account(&account, &ctx.accounts.admin, &ctx.accounts.system_program, 8 + GameState::INIT_SPACE, &game_state)?;
+        ctx.accounts.game_config.set_inner(GameConfig::with_defaults());
+
+        emit!(AccountMigrated { account: account.key(), version: ACCOUNT_VERSION });
+        msg!("GameState migrated to version {}", ACCOUNT_VERSION);
+        Ok(())
+    }
+
+    // Permissionless: the data is only carried over, so a crank can migrate every player.
+    // The baseline did not count players, so each migrated position joins its faction's count.
+    pub fn migrate_user_position(ctx: Context<MigrateUserPosition>) -> Result<()> {
+        let account = ctx.accounts.user_position.to_account_info();
+        require!(account.data_len() == LEGACY_USER_POSITION_SPACE, ZolError::AlreadyMigrated);
+        let legacy: LegacyUserPosition = read_legacy(&account.try_borrow_data()?, UserPosition::DISCRIMINATOR)?;
+
+        let game_state = &mut ctx.accounts.game_state;
+        let position = legacy.upgrade()?;
+        require!((position.faction_id as usize) < game_state.factions.len(), ZolError::InvalidFaction);
+        write_migrated_account(&account, &ctx.accounts.payer, &ctx.accounts.system_program, 8 + UserPosition::INIT_SPACE, &position)?;
+
+        let faction = &mut game_state.factions[position.faction_id as usize];
+        faction.player_count = faction.player_count.checked_add(1).unwrap();
+
+        emit!(AccountMigrated { account: account.key(), version: ACCOUNT_VERSION });
+        msg!("UserPosition of {} migrated to version {}", ctx.accounts.user.key(), ACCOUNT_VERSION);
+        Ok(())
+    }
+
+    // --- Treasury ---
+    // Moves item revenue out of the shop treasury PDA. Every withdrawal is an event, so the
+    // treasury can be audited from logs alone.
+    pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+        require!(amount > 0 && amount <= ctx.accounts.shop_treasury.amount, ZolError::InsufficientFunds);
+
+        let bump = ctx.bumps.shop_treasury;
+        let seeds = &[b"shop_treasury".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.shop_treasury.to_account_info(),
+            to: ctx.accounts.destination.to_account_info(),
+            authority: ctx.accounts.shop_treasury.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, amount)?;
+
+        emit!(TreasuryWithdrawn {
+            treasurer: ctx.accounts.treasurer.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+            remaining: ctx.accounts.shop_treasury.amount - amount,
+        });
+        msg!("Treasury: withdrew {} USDC", amount);
+        Ok(())
+    }
+
+    // --- Dev/Mock Tools ---
+
+    // Simulates the Vault earning interest from an external protocol.
+    // The Admin (or a Faucet) injects "free" USDC into the vault.
+    pub fn inject_yield(ctx: Context<InjectYield>, amount: u64) -> Result<()> {
+        // Transfer USDC from Admin/Caller to Vault
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.provider_usdc.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.provider.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+
+        // Note: We do NOT update total_tvl or user deposits.
+        // This "extra" balance in the vault represents the Yield waiting to be distributed.
+        
+        emit!(YieldInjected { provider: ctx.accounts.provider.key(), amount });
+        msg!("Simulated Yield Injection: +{} USDC", amount);
+        Ok(())
+    }
+}
+
+// --- Helpers ---
+
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Fails unless the game is in one of the `allowed` phases.
+fn require_status(game_state: &GameState, allowed: &[GameStatus]) -> Result<()> {
+    if allowed.contains(&game_state.status) {
+        return Ok(());
+    }
+    if game_state.status == GameStatus::Paused {
+        return err!(ZolError::GamePaused);
+    }
+    if game_state.status == GameStatus::Emergency {
+        return err!(ZolError::GameInEmergency);
+    }
+    err!(ZolError::InvalidGameStatus)
+}
+
+/// Copies the resolved epoch into its history record. settled_users grows during settlement.
+fn record_epoch(record: &mut EpochRecord, game_state: &GameState) {
+    record.version = ACCOUNT_VERSION;
+    record.epoch_number = game_state.epoch_number;
+    record.epoch_start_ts = game_state.epoch_start_ts;
+    record.epoch_end_ts = game_state.epoch_end_ts;
+    record.faction_tvls = game_state.factions.iter().map(|f| f.tvl_snapshot).collect();
+    record.average_tvls = game_state.factions.iter().map(|f| f.average_tvl).collect();
+    record.scores = game_state.factions.iter().map(|f| f.score).collect();
+    record.yield_pool = game_state.epoch_yield_pool;
+    record.settled_users = 0;
+}
+
+/// Adds `tvl * seconds` since the last accrual to the faction's time-weighted accumulator.
+/// Time after `epoch_end_ts` is not counted, so Settlement-phase compounding only weighs in next epoch.
+fn accrue_faction_tvl(faction: &mut FactionState, now: i64, epoch_end_ts: i64) {
+    let until = now.min(epoch_end_ts);
+    if until > faction.last_accrual_ts {
+        let elapsed = (until - faction.last_accrual_ts) as u128;
+        faction.tvl_time_weighted = faction.tvl_time_weighted
+            .checked_add((faction.tvl as u128).checked_mul(elapsed).unwrap())
+            .unwrap();
+        faction.last_accrual_ts = until;
+    }
+}
+
+/// Moves a position and its principal to `new_faction_id`, for switch_faction and a
+/// faction-changing reveal_deposit. Enforces the switch cooldown, restarts the user's time
+/// weight in the new faction and forfeits the epoch's yield if the config says so.
+fn move_to_faction(
+    user_position: &mut UserPosition,
+    game_state: &mut GameState,
+    game_config: &GameConfig,
+    new_faction_id: u8,
+    now: i64,
+) -> Result<()> {
+    let epoch_number = game_state.epoch_number;
+    if user_position.last_switch_epoch > 0 {
+        let next_allowed = user_position.last_switch_epoch.checked_add(game_config.switch_cooldown_epochs).unwrap();
+        require!(epoch_number >= next_allowed, ZolError::SwitchCooldownActive);
+    }
+
+    let epoch_end_ts = game_state.epoch_end_ts;
+    let amount = user_position.deposited_amount;
+    let old_faction_id = user_position.faction_id;
+
+    let old_faction = &mut game_state.factions[old_faction_id as usize];
+    accrue_faction_tvl(old_faction, now, epoch_end_ts);
+    old_faction.tvl = old_faction.tvl.checked_sub(amount).unwrap();
+    old_faction.player_count = old_faction.player_count.checked_sub(1).unwrap();
+
+    let new_faction = &mut game_state.factions[new_faction_id as usize];
+    accrue_faction_tvl(new_faction, now, epoch_end_ts);
+    new_faction.tvl = new_faction.tvl.checked_add(amount).unwrap();
+    new_faction.player_count = new_faction.player_count.checked_add(1).unwrap();
+
+    user_position.faction_id = new_faction_id;
+    user_position.last_switch_epoch = epoch_number;
+    // Time already spent counted for the old faction; the new one is weighed from now
+    accrue_user_tvl(user_position, game_state, now);
+    user_position.tvl_time_weighted = 0;
+    if game_config.switch_forfeits_yield {
+        user_position.yield_forfeited_epoch = epoch_number;
+    }
+
+    emit!(FactionSwitched {
+        user: user_position.owner,
+        from_faction_id: old_faction_id,
+        to_faction_id: new_faction_id,
+        amount,
+        yield_forfeited: game_config.switch_forfeits_yield,
+    });
+    Ok(())
+}
+
+/// Per-user twin of accrue_faction_tvl. The accumulator belongs to one epoch and starts over
+/// at epoch start the first time the position is touched in a later epoch.
+fn accrue_user_tvl(position: &mut UserPosition, game_state: &GameState, now: i64) {
+    if position.accrual_epoch != game_state.epoch_number {
+        position.accrual_epoch = game_state.epoch_number;
+        position.tvl_time_weighted = 0;
+        position.last_accrual_ts = game_state.epoch_start_ts;
+    }
+    let until = now.min(game_state.epoch_end_ts);
+    if until > position.last_accrual_ts {
+        let elapsed = (until - position.last_accrual_ts) as u128;
+        position.tvl_time_weighted = position.tvl_time_weighted
+            .checked_add((position.deposited_amount as u128).checked_mul(elapsed).unwrap())
+            .unwrap();
+        position.last_accrual_ts = until;
+    }
+}
+
+/// Average TVL over `duration` seconds of accrual. Falls back to the live TVL when no time has passed.
+fn time_weighted_average(faction: &FactionState, duration: i64) -> u64 {
+    weighted_average(faction.tvl_time_weighted, duration, faction.tvl)
+}
+
+fn weighted_average(time_weighted: u128, duration: i64, live: u64) -> u64 {
+    if duration > 0 {
+        u64::try_from(time_weighted / duration as u128).unwrap()
+    } else {
+        live
+    }
+}
+
+/// Scores factions and snapshots the yield pool. Shared by `resolve_epoch` and `force_close_epoch`.
+fn resolve_epoch_state(game_state: &mut GameState, vault_balance: u64, now: i64) -> Result<()> {
+    game_state.status = GameStatus::Settlement;
+    game_state.settled_count = 0;
+    game_state.epoch_yield_paid = 0;
+
+    // Snapshot the yield pool: anything in the vault above deposited principal.
+    // Unrevealed commitment escrow is also excluded: it goes back to its owners.
+    game_state.epoch_yield_pool = vault_balance
+        .saturating_sub(game_state.total_tvl)
+        .saturating_sub(game_state.total_committed);
+    // Close out the time-weighted accumulators and turn them into average TVL over the epoch.
+    // Deposits made a moment before resolution barely move the average, so they cannot flip scores.
+    let epoch_end_ts = game_state.epoch_end_ts;
+    let duration = epoch_end_ts.checked_sub(game_state.epoch_start_ts).unwrap();
+    for faction in game_state.factions.iter_mut() {
+        faction.tvl_snapshot = faction.tvl;
+        faction.yield_allocation = 0;
+        accrue_faction_tvl(faction, now, epoch_end_ts);
+        faction.average_tvl = time_weighted_average(faction, duration);
+        // Epoch is over: lift the fog on the final balances
+        faction.visible_tvl = faction.tvl;
+    }
+
+    if game_state.factions.iter().all(|f| f.average_tvl == 0) {
+        msg!("No TVL, skipping scoring.");
+    } else {
+        let scores = compute_scores(&game_state.factions);
+        for (faction, score) in game_state.factions.iter_mut().zip(scores) {
+            faction.score = score;
+        }
+
+        // Split the pool between winning factions by time-weighted TVL, so principal parked
+        // just before the end earns next to nothing. Losers' share stays in the vault; if
+        // nobody won, the whole pool rolls over into the next epoch.
+        let winners_tvl = game_state.factions.iter()
+            .filter(|f| f.score > 0)
+            .fold(0u64, |acc, f| acc.checked_add(f.average_tvl).unwrap());
+        let yield_pool = game_state.epoch_yield_pool;
+        for faction in game_state.factions.iter_mut() {
+            if faction.score > 0 {
+                faction.yield_allocation = mul_div(yield_pool, faction.average_tvl, winners_tvl);
+            }
+        }
+
+        msg!("Epoch Resolved. Scores: {:?}. Yield Pool: {}", 
+            game_state.factions.iter().map(|f| f.score).collect::<Vec<_>>(),
+            yield_pool
+        );
+    }
+
+    emit!(EpochResolved {
+        epoch_number: game_state.epoch_number,
+        epoch_start_ts: game_state.epoch_start_ts,
+        epoch_end_ts: game_state.epoch_end_ts,
+        faction_tvls: game_state.factions.iter().map(|f| f.tvl_snapshot).collect(),
+        average_tvls: game_state.factions.iter().map(|f| f.average_tvl).collect(),
+        scores: game_state.factions.iter().map(|f| f.score).collect(),
+        yield_allocations: game_state.factions.iter().map(|f| f.yield_allocation).collect(),
+        yield_pool: game_state.epoch_yield_pool,
+    });
+    
+    Ok(())
+}
+
+// Scores are fixed-point: 10_000 = the whole TVL (100 percentage points).
+pub const SCORE_SCALE: u128 = 10_000;
+
+/// Predator formula over each faction's prey/predator lists, in integer math so off-chain
+/// tools can reproduce it exactly. Uses `average_tvl` as each faction's weight.
+///
+/// Logic: Score = (% TVL of all Prey) - (% TVL of all Predators)
+pub fn compute_scores(factions: &[FactionState]) -> Vec<i64> {
+    let total = factions.iter().map(|f| f.average_tvl as u128).sum::<u128>();
+    let tvl_of = |ids: &[u8]| ids.iter().map(|id| factions[*id as usize].average_tvl as u128).sum::<u128>();
+    factions.iter()
+        .map(|f| score_from_tvl(tvl_of(&f.prey), tvl_of(&f.predators), total))
+        .collect()
+}
+
+/// `(prey - predator) * SCORE_SCALE / total`, with the magnitude rounded up (away from zero).
+///
+/// Rounding away from zero keeps the sign exact: any lead, however small, scores at least +1
+/// and any deficit at most -1, so `execute_settlement`'s `score <= 0` loss check only fires
+/// on a genuine tie or deficit. Returns 0 when `total` is 0.
+pub fn score_from_tvl(prey_tvl: u128, predator_tvl: u128, total: u128) -> i64 {
+    if total == 0 {
+        return 0;
+    }
+    let diff = (prey_tvl as i128) - (predator_tvl as i128);
+    let magnitude = (diff.unsigned_abs() * SCORE_SCALE + total - 1) / total;
+    let magnitude = i64::try_from(magnitude).unwrap();
+    if diff < 0 { -magnitude } else { magnitude }
+}
+
+/// Checks the faction list passed to `initialize_game`: 2..=MAX_FACTIONS factions, short names,
+/// and a consistent predator graph where every "A hunts B" edge appears in A's prey and B's predators.
+fn validate_faction_graph(configs: &[FactionConfig]) -> Result<()> {
+    let count = configs.len();
+    require!((2..=MAX_FACTIONS).contains(&count), ZolError::InvalidFactionConfig);
+
+    for (id, config) in configs.iter().enumerate() {
+        require!(
+            !config.name.is_empty() && config.name.len() <= MAX_FACTION_NAME_LEN,
+            ZolError::InvalidFactionConfig
+        );
+
+        for ids in [&config.prey, &config.predators] {
+            for (i, other) in ids.iter().enumerate() {
+                // In range, not self, no duplicates
+                require!((*other as usize) < count && *other as usize != id, ZolError::InvalidFactionConfig);
+                require!(!ids[..i].contains(other), ZolError::InvalidFactionConfig);
+            }
+        }
+        require!(!config.prey.iter().any(|p| config.predators.contains(p)), ZolError::InvalidFactionConfig);
+
+        for prey_id in config.prey.iter() {
+            require!(configs[*prey_id as usize].predators.contains(&(id as u8)), ZolError::InvalidFactionConfig);
+        }
+        for predator_id in config.predators.iter() {
+            require!(configs[*predator_id as usize].prey.contains(&(id as u8)), ZolError::InvalidFactionConfig);
+        }
+    }
+    Ok(())
+}
+
+/// Commitment preimage for commit-reveal deposits: sha256(faction_id || amount (u64 LE) || salt).
+pub fn deposit_commitment_hash(faction_id: u8, amount: u64, salt: &[u8; 32]) -> [u8; 32] {
+    hashv(&[&[faction_id], &amount.to_le_bytes(), salt]).to_bytes()
+}
+
+/// Pays `amount` out of the vault PDA to `to`.
+fn transfer_from_vault<'info>(
+    vault: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+    vault_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let seeds = &[b"vault".as_ref(), &[vault_bump]];
+    let signer_seeds = &[&seeds[..]];
+    let cpi_accounts = Transfer {
+        from: vault.to_account_info(),
+        to: to.to_account_info(),
+        authority: vault.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token::transfer(cpi_ctx, amount)
+}
+
+/// Moves Shield payouts and Sword bonuses from the shop treasury into the vault, where
+/// settlement pays them out like pool yield.
+fn fund_from_treasury<'info>(
+    treasury: &Account<'info, TokenAccount>,
+    vault: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+    treasury_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let seeds = &[b"shop_treasury".as_ref(), &[treasury_bump]];
+    let signer_seeds = &[&seeds[..]];
+    let cpi_accounts = Transfer {
+        from: treasury.to_account_info(),
+        to: vault.to_account_info(),
+        authority: treasury.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token::transfer(cpi_ctx, amount)
+}
+
+/// Reads the ItemDefinition accounts passed as remaining accounts. Only `add_item` creates
+/// program-owned ItemDefinition accounts, so owner + discriminator checks are enough.
+fn load_item_catalog(accounts: &[AccountInfo]) -> Result<Vec<ItemDefinition>> {
+    accounts.iter().map(|info| {
+        require_keys_eq!(*info.owner, crate::ID, ZolError::InvalidItem);
+        let data = info.try_borrow_data()?;
+        ItemDefinition::try_deserialize(&mut &data[..])
+    }).collect()
+}
+
+fn find_item(catalog: &[ItemDefinition], item_id: u8) -> Result<&ItemDefinition> {
+    catalog.iter()
+        .find(|item| item.id == item_id)
+        .ok_or_else(|| error!(ZolError::MissingItemDefinition))
+}
+
+/// One catalog item as seen by execute_settlement. `index` is its position in the
+/// (definition, mint, token account) triples of the remaining accounts.
+struct SettlementItem {
+    definition: ItemDefinition,
+    index: usize,
+    balance: u64,
+    burnable: u64,  // Tokens the item authority is delegated to burn
+    delegated: u64, // Delegated amount left for the item authority, 0 if delegated elsewhere
+}
+
+/// Reads the settlement item triples. The whole catalog must be there, once per item, so a
+/// crank can't skip a Shield by leaving it out. Token accounts must be the owner's associated
+/// accounts, the same ones buy_item mints into, so a crank can't pick an empty side account.
+fn load_settlement_items(
+    accounts: &[AccountInfo],
+    owner: &Pubkey,
+    item_authority: &Pubkey,
+    item_count: u8,
+) -> Result<Vec<SettlementItem>> {
+    require!(accounts.len() == item_count as usize * 3, ZolError::MissingItemDefinition);
+
+    let mut items: Vec<SettlementItem> = Vec::with_capacity(item_count as usize);
+    for (index, chunk) in accounts.chunks_exact(3).enumerate() {
+        let definition = load_item_catalog(&chunk[..1])?.remove(0);
+        require!(items.iter().all(|item| item.definition.id != definition.id), ZolError::InvalidItem);
+        require_keys_eq!(chunk[1].key(), definition.mint, ZolError::InvalidItemAccount);
+        require_keys_eq!(chunk[2].key(), get_associated_token_address(owner, &definition.mint), ZolError::InvalidItemAccount);
+
+        // A player who never held the item may not have the account yet: that's a zero balance
+        if chunk[2].data_is_empty() {
+            items.push(SettlementItem { definition, index, balance: 0, burnable: 0, delegated: 0 });
+            continue;
+        }
+        require_keys_eq!(*chunk[2].owner, token::ID, ZolError::InvalidItemAccount);
+        let token_account = TokenAccount::try_deserialize(&mut &chunk[2].try_borrow_data()?[..])?;
+        require_keys_eq!(token_account.mint, definition.mint, ZolError::InvalidItemAccount);
+        require_keys_eq!(token_account.owner, *owner, ZolError::InvalidItemAccount);
+
+        let delegated = if token_account.delegate == COption::Some(*item_authority) {
+            token_account.delegated_amount
+        } else {
+            0
+        };
+        let burnable = delegated.min(token_account.amount);
+        items.push(SettlementItem { definition, index, balance: token_account.amount, burnable, delegated });
+    }
+    Ok(items)
+}
+
+/// True if holding `quantity` more of `item` on top of `held` stays within its per-user cap (0 = uncapped).
+/// `held` is the balance of one token account, so this is a soft limit: items are plain SPL tokens
+/// and a player can move them to another wallet and buy again. Do not rely on it for scarcity.
+fn within_item_cap(item: &ItemDefinition, held: u64, quantity: u64) -> bool {
+    if item.max_per_user == 0 {
+        return true;
+    }
+    held.checked_add(quantity).map_or(false, |total| total <= item.max_per_user)
+}
+
+/// Mints `amount` item tokens, signed by the item authority PDA.
+fn mint_item<'info>(
+    mint: &Account<'info, Mint>,
+    to: &Account<'info, TokenAccount>,
+    item_authority: &UncheckedAccount<'info>,
+    token_program: &Program<'info, Token>,
+    authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let seeds = &[b"item_authority".as_ref(), &[authority_bump]];
+    let signer_seeds = &[&seeds[..]];
+    let cpi_accounts = MintTo {
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority: item_authority.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token::mint_to(cpi_ctx, amount)
+}
+
+/// Lets the item authority burn the user's items, so settlement (run by a crank without the
+/// user's signature) can consume a Shield or Sword. Replaces any earlier delegate on the account.
+fn approve_item_authority<'info>(
+    token_account: &Account<'info, TokenAccount>,
+    item_authority: &UncheckedAccount<'info>,
+    user: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let cpi_accounts = Approve {
+        to: token_account.to_account_info(),
+        delegate: item_authority.to_account_info(),
+        authority: user.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(token_program.to_account_info(), cpi_accounts);
+    token::approve(cpi_ctx, u64::MAX)
+}
+
+/// Parses a baseline account: the discriminator is unchanged, the fields follow in the old layout.
+fn read_legacy<T: AnchorDeserialize>(data: &[u8], discriminator: [u8; 8]) -> Result<T> {
+    require!(data.len() > 8 && data[..8] == discriminator, anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch);
+    T::deserialize(&mut &data[8..]).map_err(|_| error!(anchor_lang::error::ErrorCode::AccountDidNotDeserialize))
+}
+
+/// Reallocs a legacy account to `space`, writes `migrated` over it and settles the rent
+/// difference with `payer`.
+fn write_migrated_account<'info, T: AccountSerialize>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+    migrated: &T,
+) -> Result<()> {
+    account.realloc(space, false)?;
+    {
+        let mut data = account.try_borrow_mut_data()?;
+        data.fill(0);
+        migrated.try_serialize(&mut &mut data[..])?;
+    }
+
+    let rent = Rent::get()?.minimum_balance(space);
+    let lamports = account.lamports();
+    match lamports.cmp(&rent) {
+        std::cmp::Ordering::Less => {
+            let cpi_accounts = anchor_lang::system_program::Transfer {
+                from: payer.to_account_info(),
+                to: account.clone(),
+            };
+            let cpi_ctx = CpiContext::new(system_program.to_account_info(), cpi_accounts);
+            anchor_lang::system_program::transfer(cpi_ctx, rent - lamports)?;
+        }
+        std::cmp::Ordering::Greater => {
+            // Shrunk: the padding's rent goes back to the payer
+            **account.try_borrow_mut_lamports()? -= lamports - rent;
+            **payer.to_account_info().try_borrow_mut_lamports()? += lamports - rent;
+        }
+        std::cmp::Ordering::Equal => {}
+    }
+    Ok(())
+}
+
+fn validate_item_economics(price: u64, effect: ItemEffect, effect_magnitude: u64) -> Result<()> {
+    require!(price > 0, ZolError::InvalidItem);
+    if effect == ItemEffect::YieldBoost {
+        require!(effect_magnitude <= BPS_DENOMINATOR, ZolError::InvalidItem);
+    }
+    Ok(())
+}
+
+/// `amount * numerator / denominator` in u128, rounded down. Returns 0 when `denominator` is 0.
+pub fn mul_div(amount: u64, numerator: u64, denominator: u64) -> u64 {
+    if denominator == 0 {
+        return 0;
+    }
+    let result = (amount as u128) * (numerator as u128) / (denominator as u128);
+    u64::try_from(result).unwrap()
+}
+
+// --- Data Structures ---
+
+pub const MAX_FACTIONS: usize = 8;
+pub const MAX_FACTION_NAME_LEN: usize = 32;
+
+// Bump when an account layout changes, together with a migrate_* instruction for that account.
+pub const ACCOUNT_VERSION: u8 = 1;
+
+// --- Baseline (pre-versioning) layouts ---
+// Frozen copies of the first devnet deploy, read only by the migrate_* instructions.
+// Its accounts are told apart from current ones by their allocated size.
+pub const LEGACY_GAME_STATE_SPACE: usize = 8 + 32 + 8 + 8 + 8 + 8 + (4 + 50 + 8 + 8) * 3 + 1 + 100; // 383
+pub const LEGACY_USER_POSITION_SPACE: usize = 8 + 32 + 1 + 8 + 8 + (1 + 8 + 1 + 8 + 1) + (8 * 3) + 50; // 150
+
+// Items were hardcoded before the catalog: 1 Sword, 2 Shield, 3 Spyglass. The catalog has to
+// be recreated with these ids for migrated inventories and automation rules to line up.
+pub const LEGACY_ITEM_IDS: [u8; 3] = [1, 2, 3];
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyGameState {
+    pub admin: Pubkey,
+    pub epoch_number: u64,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+    pub total_tvl: u64,
+    pub factions: [LegacyFactionState; 3], // Vanguard, Mage, Assassin
+    pub status: GameStatus,                // Active, Settlement or Paused: same discriminants as now
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct LegacyFactionState {
+    pub id: u8,
+    pub name: String,
+    pub tvl: u64,
+    pub score: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyUserPosition {
+    pub owner: Pubkey,
+    pub faction_id: u8,
+    pub deposited_amount: u64,
+    pub last_deposit_epoch: u64,
+    pub automation_settings: AutomationSettings, // Layout unchanged since
+    pub inventory: [u64; 3],                     // Counters for LEGACY_ITEM_IDS
+}
+
+impl LegacyGameState {
+    /// The baseline game at the current layout. The faction graph was the fixed
+    /// Vanguard -> Assassin -> Mage -> Vanguard cycle, and the TVL is taken as held since
+    /// epoch start. A baseline Settlement phase had no on-chain allocations, so it goes back to
+    /// Active and resolve_epoch settles it again from the vault surplus (yield already paid
+    /// out has left the vault, so it isn't paid twice).
+    pub fn upgrade(self, usdc_mint: Pubkey, now: i64) -> Result<GameState> {
+        let mut factions = Vec::with_capacity(self.factions.len());
+        for (id, legacy) in self.factions.into_iter().enumerate() {
+            require!(legacy.name.len() <= MAX_FACTION_NAME_LEN, ZolError::InvalidFactionConfig);
+            let mut faction = FactionState {
+                id: id as u8,
+                name: legacy.name,
+                tvl: legacy.tvl,
+                score: legacy.score,
+                visible_tvl: legacy.tvl,
+                last_accrual_ts: self.epoch_start_ts,
+                prey: vec![((id + 2) % 3) as u8],
+                predators: vec![((id + 1) % 3) as u8],
+                ..FactionState::default()
+            };
+            accrue_faction_tvl(&mut faction, now, self.epoch_end_ts);
+            factions.push(faction);
+        }
+        let status = match self.status {
+            GameStatus::Settlement => GameStatus::Active,
+            status => status,
+        };
+        Ok(GameState {
+            version: ACCOUNT_VERSION,
+            admin: self.admin,
+            epoch_number: self.epoch_number,
+            epoch_start_ts: self.epoch_start_ts,
+            epoch_end_ts: self.epoch_end_ts,
+            total_tvl: self.total_tvl,
+            epoch_yield_pool: 0,
+            factions,
+            status,
+            status_before_pause: GameStatus::Active,
+            settled_count: 0,
+            total_committed: 0,
+            pending_admin: None,
+            roles: RoleAssignments::default(),
+            usdc_mint,
+            epoch_yield_paid: 0,
+        })
+    }
+}
+
+impl LegacyUserPosition {
+    /// The baseline position at the current layout. Item counters become inventory slots,
+    /// which migrate_inventory then mints out as SPL tokens.
+    pub fn upgrade(self) -> Result<UserPosition> {
+        let mut inventory = UserInventory::default();
+        for (item_id, count) in LEGACY_ITEM_IDS.into_iter().zip(self.inventory) {
+            if count > 0 {
+                inventory.add(item_id, count)?;
+            }
+        }
+        Ok(UserPosition {
+            version: ACCOUNT_VERSION,
+            owner: self.owner,
+            faction_id: self.faction_id,
+            deposited_amount: self.deposited_amount,
+            last_deposit_epoch: self.last_deposit_epoch,
+            last_settled_epoch: 0,
+            last_switch_epoch: 0,
+            yield_forfeited_epoch: 0,
+            automation_settings: self.automation_settings,
+            inventory,
+            tvl_time_weighted: 0, // Rebuilt from deposited_amount on the next accrual
+            last_accrual_ts: 0,
+            accrual_epoch: 0,
+        })
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct GameState {
+    pub version: u8, // Layout version, ACCOUNT_VERSION at creation and raised by the migrate_* instructions
+    pub admin: Pubkey,
+    pub epoch_number: u64,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+    pub total_tvl: u64,
+    pub epoch_yield_pool: u64, // Vault balance above total_tvl, recorded at resolve_epoch
+    #[max_len(MAX_FACTIONS)]
+    pub factions: Vec<FactionState>, // Up to MAX_FACTIONS, indexed by faction id
+    pub status: GameStatus,
+    pub status_before_pause: GameStatus, // Restored by unpause_game
+    pub settled_count: u64,              // Users settled in the current Settlement phase
+    pub total_committed: u64,            // Escrow held in the vault for unrevealed deposit commitments
+    pub pending_admin: Option<Pubkey>,   // Proposed by propose_admin, becomes admin on accept_admin
+    pub roles: RoleAssignments,          // Delegated keys, managed by grant_role / revoke_role
+    pub usdc_mint: Pubkey,               // Mint of the vault, set by init_vault
+    pub epoch_yield_paid: u64,           // Pool yield settled so far this epoch, never above epoch_yield_pool
+}
+
+impl GameState {
+    /// True if `key` holds `role`. The admin implicitly holds every role.
+    pub fn has_role(&self, role: Role, key: &Pubkey) -> bool {
+        *key == self.admin || self.roles.holder(role) == Some(*key)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Cranker,     // force_close_epoch
+    Pauser,      // pause_game (unpausing stays with the admin)
+    Treasurer,   // withdraw_treasury
+    ConfigAdmin, // update_config, add_item, update_item
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct RoleAssignments {
+    pub cranker: Option<Pubkey>,
+    pub pauser: Option<Pubkey>,
+    pub treasurer: Option<Pubkey>,
+    pub config_admin: Option<Pubkey>,
+}
+
+impl RoleAssignments {
+    pub fn holder(&self, role: Role) -> Option<Pubkey> {
+        match role {
+            Role::Cranker => self.cranker,
+            Role::Pauser => self.pauser,
+            Role::Treasurer => self.treasurer,
+            Role::ConfigAdmin => self.config_admin,
+        }
+    }
+
+    fn slot_mut(&mut self, role: Role) -> &mut Option<Pubkey> {
+        match role {
+            Role::Cranker => &mut self.cranker,
+            Role::Pauser => &mut self.pauser,
+            Role::Treasurer => &mut self.treasurer,
+            Role::ConfigAdmin => &mut self.config_admin,
+        }
+    }
+}
+
+// Defaults written into GameConfig by initialize_game
+pub const DEFAULT_EPOCH_DURATION: i64 = 259200;          // 3 days in seconds
+pub const DEFAULT_SETTLEMENT_GRACE_PERIOD: i64 = 86400;  // 1 day in seconds
+pub const DEFAULT_SWITCH_COOLDOWN_EPOCHS: u64 = 1;
+pub const DEFAULT_REVEAL_WINDOW: i64 = 43200;            // Last 12 hours of the epoch
+pub const DEFAULT_MARKETPLACE_FEE_BPS: u64 = 250;        // 2.5% of each marketplace sale
+pub const DEFAULT_EMERGENCY_TIMEOUT: i64 = 604800;       // 7 days unresolved past epoch end
+pub const MIN_EMERGENCY_TIMEOUT: i64 = 86400;            // Floor on top of the grace period: 1 day
+
+// Admin-tunable economics. Read by the instructions instead of compiled-in constants.
+#[account]
+#[derive(InitSpace)]
+pub struct GameConfig {
+    pub version: u8,
+    pub epoch_duration: i64,          // Seconds per epoch
+    pub settlement_grace_period: i64, // Seconds after epoch end before start_new_epoch may skip unsettled players
+    pub switch_cooldown_epochs: u64,  // Epochs between faction switches for one user
+    pub switch_forfeits_yield: bool,  // Switching forfeits the current epoch's yield
+    pub commit_reveal_enabled: bool,  // Deposits go through commit_deposit / reveal_deposit instead of deposit
+    pub reveal_window: i64,           // Seconds before epoch end reserved for reveals
+    pub item_count: u8,               // Items in the catalog, all of them are passed to settlement
+    pub marketplace_fee_bps: u64,     // Cut of every fill_listing sale sent to the shop treasury
+    pub emergency_timeout: i64,       // Seconds an epoch may stay unresolved past its end before trigger_emergency
+}
+
+impl GameConfig {
+    /// Config of a fresh game (initialize_game) or of one migrated from the baseline deploy.
+    pub fn with_defaults() -> Self {
+        GameConfig {
+            version: ACCOUNT_VERSION,
+            epoch_duration: DEFAULT_EPOCH_DURATION,
+            settlement_grace_period: DEFAULT_SETTLEMENT_GRACE_PERIOD,
+            switch_cooldown_epochs: DEFAULT_SWITCH_COOLDOWN_EPOCHS,
+            switch_forfeits_yield: true,
+            commit_reveal_enabled: false,
+            reveal_window: DEFAULT_REVEAL_WINDOW,
+            item_count: 0,
+            marketplace_fee_bps: DEFAULT_MARKETPLACE_FEE_BPS,
+            emergency_timeout: DEFAULT_EMERGENCY_TIMEOUT,
+        }
+    }
+}
+
+// update_config input, mirrors GameConfig
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct GameConfigParams {
+    pub epoch_duration: i64,
+    pub settlement_grace_period: i64,
+    pub switch_cooldown_epochs: u64,
+    pub switch_forfeits_yield: bool,
+    pub commit_reveal_enabled: bool,
+    pub reveal_window: i64,
+    pub marketplace_fee_bps: u64,
+    pub emergency_timeout: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, InitSpace)]
+pub struct FactionState {
+    pub id: u8,
+    #[max_len(MAX_FACTION_NAME_LEN)]
+    pub name: String,
+    pub tvl: u64,
+    pub score: i64,
+    pub tvl_snapshot: u64,     // TVL at resolve_epoch, denominator for user shares
+    pub yield_allocation: u64, // This faction's slice of epoch_yield_pool
+    pub player_count: u64,
+    pub tvl_time_weighted: u128, // Sum of tvl * seconds over the current epoch
+    pub last_accrual_ts: i64,    // Last time tvl_time_weighted was brought up to date
+    pub average_tvl: u64,        // Time-weighted average TVL, set at resolve_epoch and used for scoring
+    pub visible_tvl: u64,        // TVL as of the last epoch boundary, what the UI shows (fog of war). Live `tvl` is public too
+    #[max_len(MAX_FACTIONS)]
+    pub prey: Vec<u8>,           // Faction ids this faction hunts (their TVL share raises our score)
+    #[max_len(MAX_FACTIONS)]
+    pub predators: Vec<u8>,      // Faction ids hunting this faction (their TVL share lowers our score)
+}
+
+// initialize_game input: one entry per faction, ids are assigned by position.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct FactionConfig {
+    pub name: String,
+    pub prey: Vec<u8>,
+    pub predators: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum GameStatus {
+    Active,
+    Settlement,
+    Paused,
+    Emergency, // Terminal: only emergency_withdraw works
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct UserPosition {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub faction_id: u8,
+    pub deposited_amount: u64,
+    pub last_deposit_epoch: u64,
+    pub last_settled_epoch: u64, // Epoch of the last execute_settlement, 0 if never settled
+    pub last_switch_epoch: u64,  // Epoch of the last switch_faction, 0 if never switched
+    pub yield_forfeited_epoch: u64, // Epoch whose yield was given up by switching, 0 if none
+    
+    // New Advanced x402 Config
+    pub automation_settings: AutomationSettings,
+    
+    // Legacy item counters. Items now live in the user's wallet as SPL tokens;
+    // migrate_inventory moves what is left here over.
+    pub inventory: UserInventory,
+
+    // Time-weighted principal for the yield split, mirrors FactionState
+    pub tvl_time_weighted: u128, // Sum of deposited_amount * seconds over accrual_epoch
+    pub last_accrual_ts: i64,
+    pub accrual_epoch: u64,      // Epoch the accumulator belongs to
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct AutomationSettings {
+    pub priority_slot_1: AutomationRule,
+    pub priority_slot_2: AutomationRule,
+    pub fallback_action: FallbackAction,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct AutomationRule {
+    pub item_id: u8, // 0=None, otherwise an ItemDefinition id
+    pub threshold: u64, // Trigger buy if yield >= this amount
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, InitSpace)]
+pub enum FallbackAction {
+    #[default]
+    AutoCompound,
+    SendToWallet,
+}
+
+pub const MAX_INVENTORY_SLOTS: usize = 8;
+
+// One counter per catalog item held, at most MAX_INVENTORY_SLOTS distinct items.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, InitSpace)]
+pub struct UserInventory {
+    #[max_len(MAX_INVENTORY_SLOTS)]
+    pub items: Vec<ItemBalance>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct ItemBalance {
+    pub item_id: u8,
+    pub count: u64,
+}
+
+impl UserInventory {
+    pub fn count(&self, item_id: u8) -> u64 {
+        self.items.iter().find(|slot| slot.item_id == item_id).map_or(0, |slot| slot.count)
+    }
+
+    pub fn has_room_for(&self, item_id: u8) -> bool {
+        self.items.len() < MAX_INVENTORY_SLOTS || self.items.iter().any(|slot| slot.item_id == item_id)
+    }
+
+    pub fn add(&mut self, item_id: u8, amount: u64) -> Result<()> {
+        if let Some(slot) = self.items.iter_mut().find(|slot| slot.item_id == item_id) {
+            slot.count = slot.count.checked_add(amount).unwrap();
+            return Ok(());
+        }
+        require!(self.items.len() < MAX_INVENTORY_SLOTS, ZolError::InventoryFull);
+        self.items.push(ItemBalance { item_id, count: amount });
+        Ok(())
+    }
+
+    // Empty slots are dropped so they free up room for other items.
+    pub fn remove(&mut self, item_id: u8, amount: u64) -> Result<()> {
+        let index = self.items.iter().position(|slot| slot.item_id == item_id)
+            .ok_or_else(|| error!(ZolError::InsufficientItems))?;
+        let slot = &mut self.items[index];
+        slot.count = slot.count.checked_sub(amount).ok_or_else(|| error!(ZolError::InsufficientItems))?;
+        if slot.count == 0 {
+            self.items.remove(index);
+        }
+        Ok(())
+    }
+}
+
+// Hidden deposit for one user and epoch, closed on reveal or refund.
+#[account]
+#[derive(InitSpace)]
+pub struct DepositCommitment {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub epoch_number: u64,
+    pub commitment: [u8; 32], // deposit_commitment_hash(faction_id, amount, salt)
+    pub escrowed_amount: u64, // Upper bound on the hidden amount, refunded minus what is revealed
+    pub committed_at: i64,
+}
+
+// Permanent result of one epoch, PDA ["epoch", epoch_number (u64 LE)], written by resolve_epoch.
+// Per-faction vectors are indexed by faction id.
+#[account]
+#[derive(InitSpace)]
+pub struct EpochRecord {
+    pub version: u8,
+    pub epoch_number: u64,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+    #[max_len(MAX_FACTIONS)]
+    pub faction_tvls: Vec<u64>, // TVL at resolution
+    #[max_len(MAX_FACTIONS)]
+    pub average_tvls: Vec<u64>, // Time-weighted average used for scoring
+    #[max_len(MAX_FACTIONS)]
+    pub scores: Vec<i64>,
+    pub yield_pool: u64,        // Yield available to the winners
+    pub settled_users: u64,     // Users settled via execute_settlement
+}
+
+// What execute_settlement did for one user, PDA ["receipt", owner, epoch_number (u64 LE)].
+#[account]
+#[derive(InitSpace)]
+pub struct SettlementReceipt {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub epoch_number: u64,
+    pub faction_id: u8,
+    pub gross_yield: u64,      // Pro-rata share of the faction allocation, before Shield or Sword
+    pub yield_forfeited: bool, // Switched faction this epoch, nothing was paid
+    pub shield_applied: bool,  // Lost and burned an insurance item for its flat payout
+    pub sword_applied: bool,   // Won and a yield boost item applied
+    #[max_len(2)]
+    pub items_bought: Vec<u8>, // Item ids bought by the automation slots, one per slot
+    pub compounded: u64,       // Added to deposited_amount
+    pub sent_to_wallet: u64,   // Paid to the user's USDC account
+    pub payer: Pubkey,         // Paid the rent, refunded by close_receipt
+    pub settled_at: i64,
+}
+
+// Snapshot written by use_spyglass. One per user, overwritten on every use. Every input is
+// public GameState, so this is a convenience projection, not hidden information.
+#[account]
+#[derive(InitSpace)]
+pub struct IntelReport {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub epoch_number: u64,
+    pub slot: u64,
+    pub timestamp: i64,
+    #[max_len(MAX_FACTIONS)]
+    pub faction_tvls: Vec<u64>,      // Live TVL per faction
+    #[max_len(MAX_FACTIONS)]
+    pub average_tvls: Vec<u64>,      // Time-weighted average so far this epoch
+    #[max_len(MAX_FACTIONS)]
+    pub projected_scores: Vec<i64>,  // Scores if the epoch resolved now
+}
+
+pub const MAX_ITEM_NAME_LEN: usize = 32;
+// Items are retired, never removed, and settlement takes 3 accounts per catalog item, so this
+// keeps execute_settlement within the transaction account limit (with a lookup table).
+pub const MAX_ITEMS: u8 = 8;
+
+// Shop catalog entry, one PDA per item id. Created and retired by the admin.
+#[account]
+#[derive(InitSpace)]
+pub struct ItemDefinition {
+    pub version: u8,
+    pub id: u8,
+    #[max_len(MAX_ITEM_NAME_LEN)]
+    pub name: String,
+    pub price: u64,            // USDC units
+    pub effect: ItemEffect,
+    pub effect_magnitude: u64, // YieldBoost: bps of yield. Insurance: flat USDC payout. Intel: unused
+    pub max_per_user: u64,     // Soft cap on the receiving wallet's balance per purchase, 0 = uncapped
+    pub enabled: bool,         // false = retired, can no longer be bought
+    pub mint: Pubkey,          // SPL mint of the item, PDA ["item_mint", id], minted by the item authority
+}
+
+// Open marketplace offer, PDA ["listing", seller, item_id]. The tokens are held by its escrow account.
+#[account]
+#[derive(InitSpace)]
+pub struct Listing {
+    pub version: u8,
+    pub seller: Pubkey,
+    pub item_id: u8,
+    pub quantity: u64,       // Items still for sale
+    pub price_per_item: u64, // USDC units
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum ItemEffect {
+    YieldBoost, // Sword: burned on a win for a bps bonus
+    Insurance,  // Shield: burned on a loss for a flat payout
+    Intel,      // Spyglass: info reveal
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutPreference {
+    SendToWallet,
+    AutoCompound,
+    BuyItem,
+}
+
+// --- Events ---
+// Structured logs for indexers, one per state change. msg! lines stay for humans.
+
+#[event]
+pub struct GameInitialized {
+    pub admin: Pubkey,
+    pub faction_count: u8,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+}
+
+#[event]
+pub struct VaultInitialized {
+    pub vault: Pubkey,
+    pub shop_treasury: Pubkey,
+    pub usdc_mint: Pubkey,
+}
+
+#[event]
+pub struct ShopTreasuryInitialized {
+    pub shop_treasury: Pubkey,
+    pub usdc_mint: Pubkey,
+}
+
+#[event]
+pub struct UserRegistered {
+    pub user: Pubkey,
+    pub faction_id: u8,
+}
+
+#[event]
+pub struct PositionClosed {
+    pub user: Pubkey,
+    pub faction_id: u8,
+}
+
+#[event]
+pub struct Deposited {
+    pub user: Pubkey,
+    pub faction_id: u8,
+    pub amount: u64,
+    pub deposited_amount: u64, // Principal after the deposit
+}
+
+#[event]
+pub struct Withdrawn {
+    pub user: Pubkey,
+    pub faction_id: u8,
+    pub amount: u64,
+    pub deposited_amount: u64, // Principal after the withdrawal
+}
+
+#[event]
+pub struct EmergencyWithdrawn {
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DepositCommitted {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub escrowed_amount: u64,
+}
+
+#[event]
+pub struct DepositRevealed {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub faction_id: u8,
+    pub amount: u64,
+    pub refunded: u64, // Escrow returned because it was not allocated
+}
+
+#[event]
+pub struct CommitmentRefunded {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub amount: u64,
+}
+
+#[event]
+pub struct FactionSwitched {
+    pub user: Pubkey,
+    pub from_faction_id: u8,
+    pub to_faction_id: u8,
+    pub amount: u64,
+    pub yield_forfeited: bool,
+}
+
+#[event]
+pub struct AutomationUpdated {
+    pub user: Pubkey,
+    pub priority_slot_1: AutomationRule,
+    pub priority_slot_2: AutomationRule,
+    pub fallback_action: FallbackAction,
+}
+
+// Per-faction vectors are indexed by faction id.
+#[event]
+pub struct EpochResolved {
+    pub epoch_number: u64,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+    pub faction_tvls: Vec<u64>,      // TVL at resolution
+    pub average_tvls: Vec<u64>,      // Time-weighted average used for scoring
+    pub scores: Vec<i64>,
+    pub yield_allocations: Vec<u64>,
+    pub yield_pool: u64,
+}
+
+#[event]
+pub struct UserSettled {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub faction_id: u8,
+}
+
+#[event]
+pub struct ShieldConsumed {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub item_id: u8,
+    pub payout: u64,
+}
+
+#[event]
+pub struct SwordApplied {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub item_id: u8,
+    pub boost_bps: u64,
+    pub bonus: u64,
+}
+
+#[event]
+pub struct ItemAutoPurchased {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub item_id: u8,
+    pub price: u64,
+}
+
+#[event]
+pub struct YieldCompounded {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub amount: u64,
+    pub deposited_amount: u64, // Principal after compounding
+}
+
+#[event]
+pub struct YieldSentToWallet {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ReceiptClosed {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+}
+
+#[event]
+pub struct EpochStarted {
+    pub epoch_number: u64,
+    pub epoch_start_ts: i64,
+    pub epoch_end_ts: i64,
+}
+
+#[event]
+pub struct ItemPurchased {
+    pub user: Pubkey,
+    pub item_id: u8,
+    pub quantity: u64,
+    pub cost: u64,
+}
+
+#[event]
+pub struct SpyglassUsed {
+    pub user: Pubkey,
+    pub epoch_number: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct InventoryMigrated {
+    pub user: Pubkey,
+    pub item_id: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ItemDelegated {
+    pub user: Pubkey,
+    pub item_id: u8,
+}
+
+#[event]
+pub struct ItemListed {
+    pub seller: Pubkey,
+    pub item_id: u8,
+    pub quantity: u64,
+    pub price_per_item: u64,
+}
+
+#[event]
+pub struct ListingCancelled {
+    pub seller: Pubkey,
+    pub item_id: u8,
+    pub quantity: u64, // Returned to the seller
+}
+
+#[event]
+pub struct ListingFilled {
+    pub seller: Pubkey,
+    pub buyer: Pubkey,
+    pub item_id: u8,
+    pub quantity: u64,
+    pub cost: u64,
+    pub fee: u64,
+}
+
+#[event]
+pub struct StatusChanged {
+    pub from: GameStatus,
+    pub to: GameStatus,
+}
+
+#[event]
+pub struct RoleGranted {
+    pub role: Role,
+    pub holder: Pubkey,
+}
+
+#[event]
+pub struct RoleRevoked {
+    pub role: Role,
+}
+
+#[event]
+pub struct AdminTransferProposed {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub previous_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferCancelled {
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub params: GameConfigParams,
+}
+
+#[event]
+pub struct ItemAdded {
+    pub item_id: u8,
+    pub mint: Pubkey,
+    pub price: u64,
+    pub effect: ItemEffect,
+    pub effect_magnitude: u64,
+    pub max_per_user: u64,
+}
+
+#[event]
+pub struct ItemUpdated {
+    pub item_id: u8,
+    pub price: u64,
+    pub effect_magnitude: u64,
+    pub max_per_user: u64,
+    pub enabled: bool,
+}
+
+#[event]
+pub struct TreasuryWithdrawn {
+    pub treasurer: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub remaining: u64, // Treasury balance after the withdrawal
+}
+
+#[event]
+pub struct AccountMigrated {
+    pub account: Pubkey,
+    pub version: u8,
+}
+
+#[event]
+pub struct YieldInjected {
+    pub provider: Pubkey,
+    pub amount: u64,
+}
+
+// --- Contexts ---
+
+#[derive(Accounts)]
+pub struct InitializeGame<'info> {
+    #[account(
+        init, 
+        payer = admin, 
+        space = 8 + GameState::INIT_SPACE,
+        seeds = [b"game_state"],
+        bump
+    )]
+    pub game_state: Account<'info, GameState>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GameConfig::INIT_SPACE,
+        seeds = [b"game_config"],
+        bump
+    )]
+    pub game_config: Account<'info, GameConfig>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitVault<'info> {
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"vault"],
+        bump,
+        token::mint = usdc_mint,
+        token::authority = vault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    // Item sales and marketplace fees, only withdraw_treasury moves funds out
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"shop_treasury"],
+        bump,
+        token::mint = usdc_mint,
+        token::authority = shop_treasury,
+    )]
+    pub shop_treasury: Account<'info, TokenAccount>,
+    pub usdc_mint: Account<'info, Mint>,
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(mut, address = game_state.admin)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitShopTreasury<'info> {
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"shop_treasury"],
+        bump,
+        token::mint = usdc_mint,
+        token::authority = shop_treasury,
+    )]
+    pub shop_treasury: Account<'info, TokenAccount>,
+    #[account(address = game_state.usdc_mint @ ZolError::InvalidUsdcMint)]
+    pub usdc_mint: Account<'info, Mint>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(mut, address = game_state.admin)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterUser<'info> {
+    #[account(
+        init, 
+        payer = user, 
+        space = 8 + UserPosition::INIT_SPACE,
+        seeds = [b"user", user.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(mut)]
+    pub game_state: Account<'info, GameState>, // Mutable to bump the faction's player count
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut, close = user, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>, // Mutable to drop the faction's player count
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+    
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CommitDeposit<'info> {
+    // Must be registered to commit
+    #[account(seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + DepositCommitment::INIT_SPACE,
+        seeds = [b"commitment", user.key().as_ref(), game_state.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub deposit_commitment: Account<'info, DepositCommitment>,
+
+    #[account(mut, seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevealDeposit<'info> {
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"commitment", user.key().as_ref(), deposit_commitment.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub deposit_commitment: Account<'info, DepositCommitment>,
+
+    #[account(mut, seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint,
+        constraint = user_usdc.owner == user_position.owner @ ZolError::InvalidTokenOwner
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RefundCommitment<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"commitment", user.key().as_ref(), deposit_commitment.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub deposit_commitment: Account<'info, DepositCommitment>,
+
+    #[account(mut, seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint,
+        constraint = user_usdc.owner == deposit_commitment.owner @ ZolError::InvalidTokenOwner
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+    
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint,
+        constraint = user_usdc.owner == user_position.owner @ ZolError::InvalidTokenOwner
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(mut, close = user, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(mut, seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint,
+        constraint = user_usdc.owner == user_position.owner @ ZolError::InvalidTokenOwner
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SwitchFaction<'info> {
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BuyItem<'info> {
+    // Must be registered to shop
+    #[account(seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(mut, address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = item_mint,
+        associated_token::authority = user
+    )]
+    pub user_item_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint
+    )]
+    pub user_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"shop_treasury"], bump)]
+    pub shop_treasury: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateInventory<'info> {
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(mut, address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = item_mint,
+        associated_token::authority = user
+    )]
+    pub user_item_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Deposit<'info> {
-    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
-    pub user_position: Account<'info, UserPosition>,
-    
-    #[account(mut, seeds = [b"game_state"], bump)]
+pub struct DelegateItem<'info> {
+    #[account(seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = item_mint,
+        associated_token::authority = user
+    )]
+    pub user_item_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ListItem<'info> {
+    #[account(seeds = [b"game_state"], bump)]
     pub game_state: Account<'info, GameState>,
 
+    #[account(seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
     #[account(
-        mut,
-        seeds = [b"vault"],
+        init,
+        payer = seller,
+        space = 8 + Listing::INIT_SPACE,
+        seeds = [b"listing".as_ref(), seller.key().as_ref(), &[item_definition.id]],
         bump
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub listing: Account<'info, Listing>,
+
+    #[account(
+        init,
+        payer = seller,
+        token::mint = item_mint,
+        token::authority = listing,
+        seeds = [b"listing_escrow", listing.key().as_ref()],
+        bump
+    )]
+    pub escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = item_mint, token::authority = seller)]
+    pub seller_item_account: Account<'info, TokenAccount>,
 
     #[account(mut)]
-    pub user_usdc: Account<'info, TokenAccount>,
-    
+    pub seller: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelListing<'info> {
+    #[account(
+        mut,
+        close = seller,
+        has_one = seller,
+        seeds = [b"listing".as_ref(), seller.key().as_ref(), &[listing.item_id]],
+        bump = listing.bump
+    )]
+    pub listing: Account<'info, Listing>,
+
+    #[account(mut, seeds = [b"listing_escrow", listing.key().as_ref()], bump)]
+    pub escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = escrow.mint, token::authority = seller)]
+    pub seller_item_account: Account<'info, TokenAccount>,
+
     #[account(mut)]
-    pub user: Signer<'info>,
-    
+    pub seller: Signer<'info>,
+
     pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
-    #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
-    pub user_position: Account<'info, UserPosition>,
-    
-    #[account(mut, seeds = [b"game_state"], bump)]
+pub struct FillListing<'info> {
+    #[account(seeds = [b"game_state"], bump)]
     pub game_state: Account<'info, GameState>,
 
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+
     #[account(
         mut,
-        seeds = [b"vault"],
-        bump
+        has_one = seller,
+        seeds = [b"listing".as_ref(), listing.seller.as_ref(), &[listing.item_id]],
+        bump = listing.bump
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub listing: Account<'info, Listing>,
+
+    #[account(seeds = [b"item".as_ref(), &[listing.item_id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
+    #[account(mut, seeds = [b"listing_escrow", listing.key().as_ref()], bump)]
+    pub escrow: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = item_mint,
+        associated_token::authority = buyer
+    )]
+    pub buyer_item_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = buyer_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint
+    )]
+    pub buyer_usdc: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::authority = seller,
+        constraint = seller_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint
+    )]
+    pub seller_usdc: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"shop_treasury"], bump)]
+    pub shop_treasury: Account<'info, TokenAccount>,
 
+    /// CHECK: Listing's seller (has_one), receives rent back when the listing sells out
     #[account(mut)]
-    pub user_usdc: Account<'info, TokenAccount>,
-    
+    pub seller: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UseSpyglass<'info> {
+    // Only proves the caller is a registered player, nothing is written to it
+    #[account(seeds = [b"user", user.key().as_ref()], bump)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+
+    #[account(seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+
+    #[account(mut, address = item_definition.mint)]
+    pub item_mint: Account<'info, Mint>,
+
+    #[account(mut, token::mint = item_mint, token::authority = user)]
+    pub user_item_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + IntelReport::INIT_SPACE,
+        seeds = [b"intel", user.key().as_ref()],
+        bump
+    )]
+    pub intel_report: Account<'info, IntelReport>,
+
     #[account(mut)]
     pub user: Signer<'info>,
-    
+
     pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
@@ -553,6 +3192,8 @@ pub struct UpdatePreference<'info> {
 pub struct UpdateAutomation<'info> {
     #[account(mut, seeds = [b"user", user.key().as_ref()], bump)]
     pub user_position: Account<'info, UserPosition>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
     pub user: Signer<'info>,
 }
 
@@ -561,8 +3202,39 @@ pub struct UpdateAutomation<'info> {
 pub struct ResolveEpoch<'info> {
     #[account(mut, seeds = [b"game_state"], bump)]
     pub game_state: Account<'info, GameState>,
-    #[account(address = game_state.admin)]
-    pub admin: Signer<'info>,
+    #[account(seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + EpochRecord::INIT_SPACE,
+        seeds = [b"epoch", game_state.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub epoch_record: Account<'info, EpochRecord>,
+    // Can be called by a bot/crank, which pays the record's rent
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ForceCloseEpoch<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = cranker,
+        space = 8 + EpochRecord::INIT_SPACE,
+        seeds = [b"epoch", game_state.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub epoch_record: Account<'info, EpochRecord>,
+    #[account(mut, constraint = game_state.has_role(Role::Cranker, &cranker.key()) @ ZolError::Unauthorized)]
+    pub cranker: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
@@ -573,34 +3245,220 @@ pub struct ExecuteSettlement<'info> {
     #[account(mut, seeds = [b"game_state"], bump)]
     pub game_state: Account<'info, GameState>,
 
+    #[account(mut, seeds = [b"epoch", game_state.epoch_number.to_le_bytes().as_ref()], bump)]
+    pub epoch_record: Account<'info, EpochRecord>,
+
     #[account(mut, seeds = [b"vault"], bump)]
     pub vault: Account<'info, TokenAccount>,
 
     // Only needed if preference is SendToWallet
-    #[account(mut)]
+    #[account(
+        mut,
+        constraint = user_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint,
+        constraint = user_usdc.owner == user_position.owner @ ZolError::InvalidTokenOwner
+    )]
     pub user_usdc: Account<'info, TokenAccount>,
     
-    // Only needed if preference is BuyItem
-    #[account(mut)]
+    // Receives automated purchases, funds Shield payouts and Sword bonuses
+    #[account(mut, seeds = [b"shop_treasury"], bump)]
     pub shop_treasury: Account<'info, TokenAccount>,
 
-    // Can be called by a bot/crank
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+
+    // init_if_needed so a repeat settlement fails on AlreadySettled, not on the allocation
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + SettlementReceipt::INIT_SPACE,
+        seeds = [b"receipt", user_position.owner.as_ref(), game_state.epoch_number.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt: Account<'info, SettlementReceipt>,
+
+    // Can be called by a bot/crank, which pays the receipt's rent
+    #[account(mut)]
+    pub payer: Signer<'info>,
     pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseReceipt<'info> {
+    #[account(
+        mut,
+        close = payer,
+        has_one = payer,
+        seeds = [b"receipt", receipt.owner.as_ref(), receipt.epoch_number.to_le_bytes().as_ref()],
+        bump,
+        constraint = receipt.epoch_number < game_state.epoch_number @ ZolError::ReceiptStillCurrent
+    )]
+    pub receipt: Account<'info, SettlementReceipt>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    /// CHECK: Rent refund destination, pinned to receipt.payer by has_one
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+    #[account(constraint = authority.key() == receipt.owner || authority.key() == receipt.payer @ ZolError::Unauthorized)]
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct StartNewEpoch<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    // Can be called by a bot/crank
+}
+
+#[derive(Accounts)]
+pub struct PauseGame<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(constraint = game_state.has_role(Role::Pauser, &pauser.key()) @ ZolError::Unauthorized)]
+    pub pauser: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TriggerEmergency<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    // Anyone can pull the switch once the timeout has passed
+}
+
+#[derive(Accounts)]
+pub struct AdminGameControl<'info> {
     #[account(mut, seeds = [b"game_state"], bump)]
     pub game_state: Account<'info, GameState>,
     #[account(address = game_state.admin)]
     pub admin: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(constraint = game_state.pending_admin == Some(new_admin.key()) @ ZolError::NotPendingAdmin)]
+    pub new_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(constraint = game_state.has_role(Role::ConfigAdmin, &config_admin.key()) @ ZolError::Unauthorized)]
+    pub config_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(item_id: u8)]
+pub struct AddItem<'info> {
+    #[account(
+        init,
+        payer = config_admin,
+        space = 8 + ItemDefinition::INIT_SPACE,
+        seeds = [b"item".as_ref(), &[item_id]],
+        bump
+    )]
+    pub item_definition: Account<'info, ItemDefinition>,
+    #[account(
+        init,
+        payer = config_admin,
+        seeds = [b"item_mint".as_ref(), &[item_id]],
+        bump,
+        mint::decimals = 0,
+        mint::authority = item_authority
+    )]
+    pub item_mint: Account<'info, Mint>,
+    /// CHECK: PDA signer for item mints and burns, holds no data
+    #[account(seeds = [b"item_authority"], bump)]
+    pub item_authority: UncheckedAccount<'info>,
+    #[account(mut, seeds = [b"game_config"], bump)]
+    pub game_config: Account<'info, GameConfig>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(mut, constraint = game_state.has_role(Role::ConfigAdmin, &config_admin.key()) @ ZolError::Unauthorized)]
+    pub config_admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateItem<'info> {
+    #[account(mut, seeds = [b"item".as_ref(), &[item_definition.id]], bump)]
+    pub item_definition: Account<'info, ItemDefinition>,
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(constraint = game_state.has_role(Role::ConfigAdmin, &config_admin.key()) @ ZolError::Unauthorized)]
+    pub config_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTreasury<'info> {
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
+    #[account(mut, seeds = [b"shop_treasury"], bump)]
+    pub shop_treasury: Account<'info, TokenAccount>,
+    #[account(mut, constraint = destination.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint)]
+    pub destination: Account<'info, TokenAccount>,
+    #[account(constraint = game_state.has_role(Role::Treasurer, &treasurer.key()) @ ZolError::Unauthorized)]
+    pub treasurer: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Legacy layouts no longer deserialize, so the accounts are taken unchecked and
+// only their address and owner are verified here. The layout is checked by length.
+#[derive(Accounts)]
+pub struct MigrateGameState<'info> {
+    /// CHECK: Seeds and program ownership checked, legacy layout checked in the handler
+    #[account(mut, seeds = [b"game_state"], bump, owner = crate::ID)]
+    pub game_state: UncheckedAccount<'info>,
+    // Never exists on a baseline deploy. init_if_needed so a current game gets AlreadyMigrated
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = 8 + GameConfig::INIT_SPACE,
+        seeds = [b"game_config"],
+        bump
+    )]
+    pub game_config: Account<'info, GameConfig>,
+    #[account(seeds = [b"vault"], bump)]
+    pub vault: Account<'info, TokenAccount>, // Source of usdc_mint
+    #[account(mut)]
+    pub admin: Signer<'info>, // Matched against the legacy admin field, pays any extra rent
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserPosition<'info> {
+    /// CHECK: Seeds and program ownership checked, legacy layout checked in the handler
+    #[account(mut, seeds = [b"user", user.key().as_ref()], bump, owner = crate::ID)]
+    pub user_position: UncheckedAccount<'info>,
+    /// CHECK: Owner of the position, only used for the seeds
+    pub user: UncheckedAccount<'info>,
+    #[account(mut, seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>, // Migrated first; counts the player
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct InjectYield<'info> {
+    #[account(seeds = [b"game_state"], bump)]
+    pub game_state: Account<'info, GameState>,
     #[account(mut, seeds = [b"vault"], bump)]
     pub vault: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, constraint = provider_usdc.mint == game_state.usdc_mint @ ZolError::InvalidUsdcMint)]
     pub provider_usdc: Account<'info, TokenAccount>,
     #[account(mut)]
     pub provider: Signer<'info>,
@@ -609,10 +3467,312 @@ pub struct InjectYield<'info> {
 
 #[error_code]
 pub enum ZolError {
-    #[msg("Invalid faction ID (must be 0-2)")]
+    #[msg("Invalid faction ID")]
     InvalidFaction,
     #[msg("Insufficient funds for withdrawal")]
     InsufficientFunds,
     #[msg("Epoch has not ended yet")]
     EpochNotEnded,
+    #[msg("Epoch has ended, wait for the next one")]
+    EpochEnded,
+    #[msg("Instruction is not allowed in the current game phase")]
+    InvalidGameStatus,
+    #[msg("User has already been settled for this epoch")]
+    AlreadySettled,
+    #[msg("Game is paused")]
+    GamePaused,
+    #[msg("Players are still waiting to be settled for this epoch")]
+    SettlementIncomplete,
+    #[msg("Invalid faction list or predator graph")]
+    InvalidFactionConfig,
+    #[msg("Faction switch is still on cooldown")]
+    SwitchCooldownActive,
+    #[msg("Invalid game config value")]
+    InvalidConfig,
+    #[msg("Invalid item definition")]
+    InvalidItem,
+    #[msg("Item definition was not passed in remaining accounts")]
+    MissingItemDefinition,
+    #[msg("Inventory has no free slot for a new item")]
+    InventoryFull,
+    #[msg("Item catalog is full")]
+    CatalogFull,
+    #[msg("Not enough of this item in inventory")]
+    InsufficientItems,
+    #[msg("Item is retired and cannot be bought")]
+    ItemNotForSale,
+    #[msg("Purchase would exceed the item's per-user cap")]
+    ItemCapReached,
+    #[msg("Direct deposits are disabled while commit-reveal mode is on")]
+    DirectDepositDisabled,
+    #[msg("Commit-reveal mode is disabled")]
+    CommitRevealDisabled,
+    #[msg("Commit phase is over for this epoch")]
+    CommitPhaseClosed,
+    #[msg("Reveal window has not opened yet")]
+    RevealWindowNotOpen,
+    #[msg("Reveal window is closed")]
+    RevealWindowClosed,
+    #[msg("Revealed values do not match the commitment")]
+    CommitmentMismatch,
+    #[msg("Funded positions can only reveal into their own faction")]
+    FactionMismatch,
+    #[msg("Item token account does not match the item mint or owner")]
+    InvalidItemAccount,
+    #[msg("Listing quantity and price must be greater than zero")]
+    InvalidListing,
+    #[msg("Listing price is above the buyer's limit")]
+    PriceAboveLimit,
+    #[msg("No admin transfer is pending")]
+    NoPendingAdmin,
+    #[msg("Signer is not the pending admin")]
+    NotPendingAdmin,
+    #[msg("Signer does not hold the required role")]
+    Unauthorized,
+    #[msg("Role is not assigned")]
+    RoleNotAssigned,
+    #[msg("Game is in emergency mode, only emergency_withdraw is available")]
+    GameInEmergency,
+    #[msg("Epoch has not been unresolved long enough to trigger emergency mode")]
+    EmergencyTimeoutNotReached,
+    #[msg("Receipts can only be closed once their epoch is over")]
+    ReceiptStillCurrent,
+    #[msg("Position still holds a deposit or unmigrated items")]
+    PositionNotEmpty,
+    #[msg("Account is already on the current layout")]
+    AlreadyMigrated,
+    #[msg("Token account mint is not the game's USDC mint")]
+    InvalidUsdcMint,
+    #[msg("Token account is not owned by the expected wallet")]
+    InvalidTokenOwner,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn faction(id: u8, prey: &[u8], predators: &[u8], average_tvl: u64) -> FactionState {
+        FactionState { id, prey: prey.to_vec(), predators: predators.to_vec(), average_tvl, ..FactionState::default() }
+    }
+
+    // Vanguard -> Assassin -> Mage -> Vanguard
+    fn classic(tvls: [u64; 3]) -> Vec<FactionState> {
+        vec![
+            faction(0, &[2], &[1], tvls[0]),
+            faction(1, &[0], &[2], tvls[1]),
+            faction(2, &[1], &[0], tvls[2]),
+        ]
+    }
+
+    fn config(name: &str, prey: &[u8], predators: &[u8]) -> FactionConfig {
+        FactionConfig { name: name.to_string(), prey: prey.to_vec(), predators: predators.to_vec() }
+    }
+
+    #[test]
+    fn equal_tvl_is_a_draw() {
+        assert_eq!(compute_scores(&classic([100, 100, 100])), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn skewed_tvl_matches_predator_formula() {
+        // V: (300 - 100) / 500, M: (100 - 300) / 500, A: (100 - 100) / 500
+        assert_eq!(compute_scores(&classic([100, 100, 300])), vec![4_000, -4_000, 0]);
+    }
+
+    #[test]
+    fn tiny_leads_keep_their_sign() {
+        // 1 unit out of 3 billion would truncate to 0 and look like a loss.
+        let scores = compute_scores(&classic([1_000_000_000, 1_000_000_000, 1_000_000_001]));
+        assert_eq!(scores, vec![1, -1, 0]);
+    }
+
+    #[test]
+    fn rounds_magnitude_away_from_zero() {
+        // 1/3 of the scale is 3333.33.. -> 3334 / -3334
+        assert_eq!(score_from_tvl(2, 1, 3), 3_334);
+        assert_eq!(score_from_tvl(1, 2, 3), -3_334);
+    }
+
+    #[test]
+    fn no_tvl_scores_zero() {
+        assert_eq!(compute_scores(&classic([0, 0, 0])), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn handles_max_tvl_without_overflow() {
+        assert_eq!(compute_scores(&classic([u64::MAX, 0, 0])), vec![0, 10_000, -10_000]);
+    }
+
+    #[test]
+    fn five_faction_graph_sums_all_prey_and_predators() {
+        // Rock-paper-scissors-lizard-Spock: each faction hunts two and is hunted by two.
+        // 0 Rock, 1 Paper, 2 Scissors, 3 Lizard, 4 Spock
+        let factions = vec![
+            faction(0, &[2, 3], &[1, 4], 100),
+            faction(1, &[0, 4], &[2, 3], 200),
+            faction(2, &[1, 3], &[0, 4], 300),
+            faction(3, &[1, 4], &[0, 2], 0),
+            faction(4, &[0, 2], &[1, 3], 400),
+        ];
+        // Rock: (300 + 0) - (200 + 400) = -300 of 1000
+        // Paper: (100 + 400) - (300 + 0) = 200 of 1000
+        assert_eq!(compute_scores(&factions), vec![-3_000, 2_000, -3_000, 2_000, 2_000]);
+
+        let configs: Vec<FactionConfig> = factions.iter()
+            .map(|f| config("F", &f.prey, &f.predators))
+            .collect();
+        assert!(validate_faction_graph(&configs).is_ok());
+    }
+
+    #[test]
+    fn inventory_tracks_counts_and_frees_empty_slots() {
+        let mut inventory = UserInventory::default();
+        inventory.add(1, 2).unwrap();
+        inventory.add(2, 1).unwrap();
+        inventory.add(1, 1).unwrap();
+        assert_eq!(inventory.count(1), 3);
+        assert_eq!(inventory.items.len(), 2);
+
+        inventory.remove(2, 1).unwrap();
+        assert_eq!(inventory.count(2), 0);
+        assert_eq!(inventory.items.len(), 1);
+        assert!(inventory.remove(2, 1).is_err());
+    }
+
+    #[test]
+    fn inventory_is_capped_at_max_slots() {
+        let mut inventory = UserInventory::default();
+        for id in 1..=MAX_INVENTORY_SLOTS as u8 {
+            inventory.add(id, 1).unwrap();
+        }
+        assert!(!inventory.has_room_for(100));
+        assert!(inventory.add(100, 1).is_err());
+        // Existing items can still stack
+        assert!(inventory.has_room_for(1));
+        inventory.add(1, 1).unwrap();
+    }
+
+    // Baseline account bytes: discriminator, legacy fields, zero padding up to the old allocation.
+    fn legacy_bytes(discriminator: [u8; 8], account: &impl AnchorSerialize, legacy_space: usize) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        account.serialize(&mut data).unwrap();
+        assert!(data.len() <= legacy_space);
+        data.resize(legacy_space, 0);
+        data
+    }
+
+    #[test]
+    fn baseline_user_position_upgrades() {
+        let owner = Pubkey::new_unique();
+        let legacy = LegacyUserPosition {
+            owner,
+            faction_id: 2,
+            deposited_amount: 42,
+            last_deposit_epoch: 3,
+            automation_settings: AutomationSettings {
+                priority_slot_1: AutomationRule { item_id: 1, threshold: 10 },
+                fallback_action: FallbackAction::SendToWallet,
+                ..AutomationSettings::default()
+            },
+            inventory: [0, 2, 1], // No Sword, 2 Shields, 1 Spyglass
+        };
+        let data = legacy_bytes(UserPosition::DISCRIMINATOR, &legacy, LEGACY_USER_POSITION_SPACE);
+
+        let read: LegacyUserPosition = read_legacy(&data, UserPosition::DISCRIMINATOR).unwrap();
+        let migrated = read.upgrade().unwrap();
+        assert_eq!(migrated.version, ACCOUNT_VERSION);
+        assert_eq!(migrated.owner, owner);
+        assert_eq!(migrated.faction_id, 2);
+        assert_eq!(migrated.deposited_amount, 42);
+        assert_eq!(migrated.last_deposit_epoch, 3);
+        assert_eq!(migrated.automation_settings.priority_slot_1.threshold, 10);
+        assert!(migrated.automation_settings.fallback_action == FallbackAction::SendToWallet);
+        assert_eq!(migrated.inventory.items.len(), 2);
+        assert_eq!(migrated.inventory.count(2), 2);
+        assert_eq!(migrated.inventory.count(3), 1);
+
+        let mut upgraded = Vec::new();
+        migrated.try_serialize(&mut upgraded).unwrap();
+        assert!(upgraded.len() <= 8 + UserPosition::INIT_SPACE);
+        // A current account never has the baseline size, so it can't be migrated twice
+        assert_ne!(LEGACY_USER_POSITION_SPACE, 8 + UserPosition::INIT_SPACE);
+    }
+
+    #[test]
+    fn baseline_game_state_upgrades() {
+        let admin = Pubkey::new_unique();
+        let names = ["Vanguard", "Mage", "Assassin"];
+        let legacy = LegacyGameState {
+            admin,
+            epoch_number: 7,
+            epoch_start_ts: 1_000,
+            epoch_end_ts: 2_000,
+            total_tvl: 600,
+            factions: [0u8, 1, 2].map(|id| LegacyFactionState {
+                id,
+                name: names[id as usize].to_string(),
+                tvl: 100 * (id as u64 + 1),
+                score: 0,
+            }),
+            status: GameStatus::Settlement,
+        };
+        let data = legacy_bytes(GameState::DISCRIMINATOR, &legacy, LEGACY_GAME_STATE_SPACE);
+        assert!(read_legacy::<LegacyGameState>(&data, UserPosition::DISCRIMINATOR).is_err());
+
+        let usdc_mint = Pubkey::new_unique();
+        let read: LegacyGameState = read_legacy(&data, GameState::DISCRIMINATOR).unwrap();
+        let migrated = read.upgrade(usdc_mint, 1_500).unwrap();
+        assert_eq!(migrated.version, ACCOUNT_VERSION);
+        assert_eq!(migrated.admin, admin);
+        assert_eq!(migrated.epoch_number, 7);
+        assert_eq!(migrated.total_tvl, 600);
+        assert_eq!(migrated.usdc_mint, usdc_mint);
+        // Settlement had no on-chain allocations yet, so the epoch resolves again
+        assert!(migrated.status == GameStatus::Active);
+
+        // The classic cycle, with TVL counted from epoch start
+        assert_eq!(migrated.factions.len(), 3);
+        let vanguard = &migrated.factions[0];
+        assert_eq!(vanguard.name, "Vanguard");
+        assert_eq!(vanguard.tvl, 100);
+        assert_eq!(vanguard.tvl_time_weighted, 100 * 500);
+        assert_eq!((vanguard.prey.clone(), vanguard.predators.clone()), (vec![2], vec![1]));
+        let configs: Vec<FactionConfig> = migrated.factions.iter()
+            .map(|f| config(&f.name, &f.prey, &f.predators))
+            .collect();
+        assert!(validate_faction_graph(&configs).is_ok());
+
+        let mut upgraded = Vec::new();
+        migrated.try_serialize(&mut upgraded).unwrap();
+        assert!(upgraded.len() <= 8 + GameState::INIT_SPACE);
+        assert_ne!(LEGACY_GAME_STATE_SPACE, 8 + GameState::INIT_SPACE);
+    }
+
+    #[test]
+    fn accepts_consistent_graph() {
+        let configs = vec![
+            config("Vanguard", &[2], &[1]),
+            config("Mage", &[0], &[2]),
+            config("Assassin", &[1], &[0]),
+        ];
+        assert!(validate_faction_graph(&configs).is_ok());
+    }
+
+    #[test]
+    fn rejects_one_sided_edges() {
+        // Vanguard hunts Assassin, but Assassin does not list Vanguard as a predator
+        let configs = vec![
+            config("Vanguard", &[2], &[1]),
+            config("Mage", &[0], &[]),
+            config("Assassin", &[], &[]),
+        ];
+        assert!(validate_faction_graph(&configs).is_err());
+    }
+
+    #[test]
+    fn rejects_self_and_out_of_range_ids() {
+        assert!(validate_faction_graph(&[config("A", &[0], &[0]), config("B", &[], &[])]).is_err());
+        assert!(validate_faction_graph(&[config("A", &[5], &[]), config("B", &[], &[])]).is_err());
+        assert!(validate_faction_graph(&[config("A", &[], &[])]).is_err());
+    }
 }
@@ -8,9 +8,13 @@ import {
   getOrCreateAssociatedTokenAccount,
   mintTo, 
   getAccount, 
-  TOKEN_PROGRAM_ID 
+  getAssociatedTokenAddressSync,
+  transfer,
+  TOKEN_PROGRAM_ID,
+  ASSOCIATED_TOKEN_PROGRAM_ID
 } from "@solana/spl-token";
 import { assert } from "chai";
+import { createHash } from "crypto";
 
 describe("ZOL Game & x402 Engine Tests", () => {
   // Configure the client to use the local cluster.
@@ -23,10 +27,71 @@ describe("ZOL Game & x402 Engine Tests", () => {
   
   // Test State Variables
   let gameStatePda: anchor.web3.PublicKey;
+  let gameConfigPda: anchor.web3.PublicKey;
   let vaultPda: anchor.web3.PublicKey;
-  let shopTreasury: anchor.web3.Keypair;
   let usdcMint: anchor.web3.PublicKey;
+
+  // Item Catalog (ItemDefinition PDAs)
+  const SWORD_ID = 1;
+  const SHIELD_ID = 2;
+  const SPYGLASS_ID = 3;
+  const itemPda = (id: number) => anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("item"), Buffer.from([id])],
+    program.programId
+  )[0];
+  // Items are SPL tokens minted by a program PDA
+  const itemMintPda = (id: number) => anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("item_mint"), Buffer.from([id])],
+    program.programId
+  )[0];
+  const itemAuthorityPda = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("item_authority")],
+    program.programId
+  )[0];
+  const itemAta = (owner: anchor.web3.PublicKey, id: number) =>
+    getAssociatedTokenAddressSync(itemMintPda(id), owner);
+  // Automation validates rules against the item definitions in remaining accounts
+  const itemAccounts = () => [SWORD_ID, SHIELD_ID, SPYGLASS_ID].map((id) => ({
+    pubkey: itemPda(id),
+    isWritable: false,
+    isSigner: false,
+  }));
+  // Settlement takes (definition, mint, user's item account) for the whole catalog
+  const settlementAccounts = (owner: anchor.web3.PublicKey) => [SWORD_ID, SHIELD_ID, SPYGLASS_ID].flatMap((id) => [
+    { pubkey: itemPda(id), isWritable: false, isSigner: false },
+    { pubkey: itemMintPda(id), isWritable: true, isSigner: false },
+    { pubkey: itemAta(owner, id), isWritable: true, isSigner: false },
+  ]);
+  const itemCount = async (owner: anchor.web3.PublicKey, id: number): Promise<number> => {
+    const account = await getAccount(provider.connection, itemAta(owner, id));
+    return Number(account.amount);
+  };
   
+  // EpochRecord history PDA of the epoch currently being played or settled
+  const epochRecordPda = (epoch: anchor.BN) => anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("epoch"), epoch.toArrayLike(Buffer, "le", 8)],
+    program.programId
+  )[0];
+  const currentEpochRecord = async () =>
+    epochRecordPda((await program.account.gameState.fetch(gameStatePda)).epochNumber);
+  // Per-user SettlementReceipt written by execute_settlement
+  const receiptPda = (owner: anchor.web3.PublicKey, epoch: anchor.BN) => anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("receipt"), owner.toBuffer(), epoch.toArrayLike(Buffer, "le", 8)],
+    program.programId
+  )[0];
+  const currentReceipt = async (owner: anchor.web3.PublicKey) =>
+    receiptPda(owner, (await program.account.gameState.fetch(gameStatePda)).epochNumber);
+
+  // Decodes the Anchor events emitted by a confirmed transaction
+  const eventsOf = async (signature: string) => {
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    return [...parser.parseLogs(tx.meta.logMessages)];
+  };
+
   // Players
   const admin = provider.wallet;
   const player1 = anchor.web3.Keypair.generate(); // Vanguard
@@ -45,6 +110,13 @@ describe("ZOL Game & x402 Engine Tests", () => {
   let vaultUsdc: anchor.web3.PublicKey;
   let shopTreasuryUsdc: anchor.web3.PublicKey;
 
+  // Classic 3-faction cycle: Vanguard -> Assassin -> Mage -> Vanguard
+  const FACTIONS = [
+    { name: "Vanguard", prey: Buffer.from([2]), predators: Buffer.from([1]) },
+    { name: "Mage", prey: Buffer.from([0]), predators: Buffer.from([2]) },
+    { name: "Assassin", prey: Buffer.from([1]), predators: Buffer.from([0]) },
+  ];
+
   const INITIAL_DEPOSIT = new anchor.BN(100_000_000); // 100 USDC
   const MINT_AMOUNT = new anchor.BN(1_000_000_000); // 1000 USDC
 
@@ -54,6 +126,10 @@ describe("ZOL Game & x402 Engine Tests", () => {
       [Buffer.from("game_state")],
       program.programId
     );
+    [gameConfigPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("game_config")],
+      program.programId
+    );
     [vaultPda] = anchor.web3.PublicKey.findProgramAddressSync(
       [Buffer.from("vault")],
       program.programId
@@ -74,8 +150,6 @@ describe("ZOL Game & x402 Engine Tests", () => {
 
     // 2. Setup Mock USDC
     // Note: In a real local test, we need to fund the payer first
-    shopTreasury = anchor.web3.Keypair.generate();
-    
     // Fund players with SOL (Reduced to 0.1 SOL to avoid rate limits)
     try {
       // Use provider wallet to fund players instead of airdrop if airdrop fails
@@ -119,7 +193,11 @@ describe("ZOL Game & x402 Engine Tests", () => {
     // Vault needs allowOwnerOffCurve = true
     vaultUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, vaultPda, true)).address;
     
-    shopTreasuryUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, shopTreasury.publicKey)).address;
+    // Shop revenue lands in a program-owned token account, created by init_vault
+    [shopTreasuryUsdc] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("shop_treasury")],
+      program.programId
+    );
 
     // Mint to players
     await mintTo(provider.connection, (admin as any).payer, usdcMint, player1Usdc, admin.publicKey, BigInt(MINT_AMOUNT.toString()));
@@ -129,17 +207,25 @@ describe("ZOL Game & x402 Engine Tests", () => {
 
   it("Initializes the Game", async () => {
     await program.methods
-      .initializeGame()
+      .initializeGame(FACTIONS)
       .accounts({
         gameState: gameStatePda,
+        gameConfig: gameConfigPda,
         admin: admin.publicKey,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .rpc();
 
     const state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.version, 1);
     assert.equal(state.epochNumber.toNumber(), 1);
     assert.equal(state.status.active !== undefined, true);
+    assert.equal(state.factions.length, 3);
+    assert.deepEqual(Array.from(state.factions[0].prey), [2]);
+
+    const config = await program.account.gameConfig.fetch(gameConfigPda);
+    assert.equal(config.epochDuration.toNumber(), 259200);
+    assert.equal(config.switchCooldownEpochs.toNumber(), 1);
   });
 
   it("Initializes the Vault", async () => {
@@ -147,13 +233,60 @@ describe("ZOL Game & x402 Engine Tests", () => {
       .initVault()
       .accounts({
         vault: vaultPda,
+        shopTreasury: shopTreasuryUsdc,
         usdcMint: usdcMint,
+        gameState: gameStatePda,
         admin: admin.publicKey,
         systemProgram: anchor.web3.SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID,
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
       })
       .rpc();
+
+    // Every USDC account passed later is checked against this mint
+    const state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.usdcMint.toBase58(), usdcMint.toBase58());
+  });
+
+  it("Adds Items to the Catalog", async () => {
+    const items = [
+      // Sword: +20% winning yield
+      { id: SWORD_ID, name: "Sword", price: 10_000_000, effect: { yieldBoost: {} }, magnitude: 2_000, cap: 0 },
+      // Shield: flat 2 USDC insurance payout on a loss, at most 3 per player
+      { id: SHIELD_ID, name: "Shield", price: 2_000_000, effect: { insurance: {} }, magnitude: 2_000_000, cap: 3 },
+      { id: SPYGLASS_ID, name: "Spyglass", price: 5_000_000, effect: { intel: {} }, magnitude: 0, cap: 0 },
+    ];
+    for (const item of items) {
+      await program.methods
+        .addItem(item.id, item.name, new anchor.BN(item.price), item.effect as any, new anchor.BN(item.magnitude), new anchor.BN(item.cap))
+        .accounts({
+          itemDefinition: itemPda(item.id),
+          itemMint: itemMintPda(item.id),
+          itemAuthority: itemAuthorityPda,
+          gameConfig: gameConfigPda,
+          gameState: gameStatePda,
+          configAdmin: admin.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+
+    const sword = await program.account.itemDefinition.fetch(itemPda(SWORD_ID));
+    assert.equal(sword.price.toNumber(), 10_000_000);
+    assert.equal(sword.enabled, true);
+    assert.equal(sword.mint.toBase58(), itemMintPda(SWORD_ID).toBase58());
+
+    const config = await program.account.gameConfig.fetch(gameConfigPda);
+    assert.equal(config.itemCount, 3);
+
+    // Settlement treats a missing item account as empty; creating them up front keeps the
+    // balance checks below simple.
+    for (const player of [player1, player2, player3]) {
+      for (const id of [SWORD_ID, SHIELD_ID, SPYGLASS_ID]) {
+        await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, itemMintPda(id), player.publicKey);
+      }
+    }
   });
 
   it("Registers Users into Factions", async () => {
@@ -190,6 +323,7 @@ describe("ZOL Game & x402 Engine Tests", () => {
     await program.methods.deposit(INITIAL_DEPOSIT).accounts({
       userPosition: player1Pda,
       gameState: gameStatePda,
+      gameConfig: gameConfigPda,
       vault: vaultUsdc,
       userUsdc: player1Usdc,
       user: player1.publicKey,
@@ -200,6 +334,7 @@ describe("ZOL Game & x402 Engine Tests", () => {
     await program.methods.deposit(INITIAL_DEPOSIT).accounts({
       userPosition: player2Pda,
       gameState: gameStatePda,
+      gameConfig: gameConfigPda,
       vault: vaultUsdc,
       userUsdc: player2Usdc,
       user: player2.publicKey,
@@ -210,6 +345,21 @@ describe("ZOL Game & x402 Engine Tests", () => {
     await program.methods.deposit(INITIAL_DEPOSIT).accounts({
       userPosition: player3Pda,
       gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      vault: vaultUsdc,
+      userUsdc: player3Usdc,
+      user: player3.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player3]).rpc();
+
+    // Skew the TVL to make Vanguard Win.
+    // Vanguard wants Assassin (Target) to be BIG, and Mage (Predator) to be SMALL.
+    // Scores use time-weighted TVL, so the skew has to be in place early in the epoch.
+    const extraDeposit = new anchor.BN(200_000_000); // +200 USDC
+    await program.methods.deposit(extraDeposit).accounts({
+      userPosition: player3Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
       vault: vaultUsdc,
       userUsdc: player3Usdc,
       user: player3.publicKey,
@@ -217,7 +367,90 @@ describe("ZOL Game & x402 Engine Tests", () => {
     }).signers([player3]).rpc();
 
     const vaultBalance = await getAccount(provider.connection, vaultUsdc);
-    assert.equal(vaultBalance.amount.toString(), INITIAL_DEPOSIT.mul(new anchor.BN(3)).toString());
+    assert.equal(vaultBalance.amount.toString(), INITIAL_DEPOSIT.mul(new anchor.BN(5)).toString());
+  });
+
+  it("Rejects Foreign Token Accounts", async () => {
+    // A look-alike token is not USDC.
+    const fakeMint = await createMint(provider.connection, (admin as any).payer, admin.publicKey, null, 6);
+    const fakeUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, fakeMint, player1.publicKey)).address;
+    await mintTo(provider.connection, (admin as any).payer, fakeMint, fakeUsdc, admin.publicKey, BigInt(1_000_000));
+    try {
+      await program.methods.deposit(new anchor.BN(1_000_000)).accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        vault: vaultUsdc,
+        userUsdc: fakeUsdc,
+        user: player1.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player1]).rpc();
+      assert.fail("Deposit from another mint should fail");
+    } catch (e) {
+      assert.include(e.toString(), "InvalidUsdcMint");
+    }
+
+    // Principal only goes back to the position owner.
+    try {
+      await program.methods.withdraw(new anchor.BN(1_000_000)).accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        vault: vaultUsdc,
+        userUsdc: player2Usdc,
+        user: player1.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player1]).rpc();
+      assert.fail("Withdraw into someone else's account should fail");
+    } catch (e) {
+      assert.include(e.toString(), "InvalidTokenOwner");
+    }
+  });
+
+  it("Buys Shields Manually From the Shop", async () => {
+    // Player 2 (Mage) expects to lose and restocks 2 Shields with their own USDC.
+    await program.methods.buyItem(new anchor.BN(2)).accounts({
+      userPosition: player2Pda,
+      gameState: gameStatePda,
+      itemDefinition: itemPda(SHIELD_ID),
+      itemMint: itemMintPda(SHIELD_ID),
+      userItemAccount: itemAta(player2.publicKey, SHIELD_ID),
+      itemAuthority: itemAuthorityPda,
+      userUsdc: player2Usdc,
+      shopTreasury: shopTreasuryUsdc,
+      user: player2.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player2]).rpc();
+
+    // Shields are SPL tokens in P2's wallet, with the program delegated to burn them.
+    assert.equal(await itemCount(player2.publicKey, SHIELD_ID), 2);
+    const shieldAccount = await getAccount(provider.connection, itemAta(player2.publicKey, SHIELD_ID));
+    assert.equal(shieldAccount.delegate.toBase58(), itemAuthorityPda.toBase58());
+
+    const shopBal = await getAccount(provider.connection, shopTreasuryUsdc);
+    assert.equal(shopBal.amount.toString(), "4000000");
+
+    // Cap is 3 Shields: buying 2 more would overshoot.
+    try {
+      await program.methods.buyItem(new anchor.BN(2)).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        itemDefinition: itemPda(SHIELD_ID),
+        itemMint: itemMintPda(SHIELD_ID),
+        userItemAccount: itemAta(player2.publicKey, SHIELD_ID),
+        itemAuthority: itemAuthorityPda,
+        userUsdc: player2Usdc,
+        shopTreasury: shopTreasuryUsdc,
+        user: player2.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).signers([player2]).rpc();
+      assert.fail("Purchase over the cap should fail");
+    } catch (e) {
+      assert.include(e.toString(), "ItemCapReached");
+    }
   });
 
   it("Sets up x402 Automation (Player 1: Buy Sword if Win)", async () => {
@@ -237,33 +470,54 @@ describe("ZOL Game & x402 Engine Tests", () => {
       { autoCompound: {} } // Fallback
     ).accounts({
       userPosition: player1Pda,
+      gameState: gameStatePda,
       user: player1.publicKey
-    }).signers([player1]).rpc();
+    }).remainingAccounts(itemAccounts()).signers([player1]).rpc();
 
     const p1State = await program.account.userPosition.fetch(player1Pda);
     assert.equal(p1State.automationSettings.prioritySlot1.itemId, 1);
+
+    // P1 never bought a Sword by hand, so the program has no burn delegation on that account
+    // yet. Settlement only auto-buys into delegated accounts.
+    await program.methods.delegateItem().accounts({
+      itemDefinition: itemPda(SWORD_ID),
+      itemMint: itemMintPda(SWORD_ID),
+      userItemAccount: itemAta(player1.publicKey, SWORD_ID),
+      itemAuthority: itemAuthorityPda,
+      user: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player1]).rpc();
+    const swordAccount = await getAccount(provider.connection, itemAta(player1.publicKey, SWORD_ID));
+    assert.equal(swordAccount.delegate.toBase58(), itemAuthorityPda.toBase58());
   });
 
-  it("Resolves Epoch (Vanguard Wins)", async () => {
-    // Scenario:
-    // Vanguard (P1) vs Assassin (P3) [Target] - Mage (P2) [Predator]
-    // All TVL equal (33% each).
-    // Score V = %Target(33%) - %Predator(33%) = 0.
-    // Wait, if everyone deposits equal, score is 0. No one wins.
-    
-    // Let's skew the TVL to make Vanguard Win.
-    // Vanguard wants Assassin (Target) to be BIG, and Mage (Predator) to be SMALL.
-    // Let's have Player 3 (Assassin) deposit MORE.
+  it("Injects Mock Yield", async () => {
+    const yieldAmount = new anchor.BN(50_000_000); // 50 USDC Yield
     
-    const extraDeposit = new anchor.BN(200_000_000); // +200 USDC
-    await program.methods.deposit(extraDeposit).accounts({
-      userPosition: player3Pda,
+    // Admin injects yield to vault (simulating DeFi protocol return)
+    // Need to mint to admin first or use payer
+    const adminUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, admin.publicKey)).address;
+    await mintTo(provider.connection, (admin as any).payer, usdcMint, adminUsdc, admin.publicKey, BigInt(yieldAmount.toString()));
+
+    const signature = await program.methods.injectYield(yieldAmount).accounts({
       gameState: gameStatePda,
       vault: vaultUsdc,
-      userUsdc: player3Usdc,
-      user: player3.publicKey,
-      tokenProgram: TOKEN_PROGRAM_ID,
-    }).signers([player3]).rpc();
+      providerUsdc: adminUsdc,
+      provider: admin.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID
+    }).rpc({ commitment: "confirmed" });
+
+    const [event] = await eventsOf(signature);
+    assert.equal(event.name, "YieldInjected");
+    assert.equal(event.data.amount.toString(), yieldAmount.toString());
+  });
+
+  it("Resolves Epoch (Vanguard Wins)", async () => {
+    // Scenario:
+    // Vanguard (P1) vs Assassin (P3) [Target] - Mage (P2) [Predator]
+    // Player 3 (Assassin) deposited MORE at the start of the epoch.
 
     // Now:
     // P1 (Vanguard): 100
@@ -275,83 +529,274 @@ describe("ZOL Game & x402 Engine Tests", () => {
     // A Score = (100/500) - (100/500) = 0.2 - 0.2 = 0.0 (Draw)
     // M Score = (100/500) - (300/500) = 0.2 - 0.6 = -0.4 (Lose)
 
-    await program.methods.resolveEpoch().accounts({
+    // The 3-day epoch is still running, so the permissionless path is closed.
+    try {
+      await program.methods.resolveEpoch().accounts({
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        payer: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        vault: vaultUsdc,
+      }).rpc();
+      assert.fail("Resolve should fail before epoch end");
+    } catch (e) {
+      assert.include(e.toString(), "EpochNotEnded");
+    }
+
+    // Let the deposits age so the time-weighted averages settle near the balances above.
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    // Admin force-closes the epoch early for the test.
+    const signature = await program.methods.forceCloseEpoch().accounts({
       gameState: gameStatePda,
-      admin: admin.publicKey
-    }).rpc();
+      epochRecord: await currentEpochRecord(),
+      systemProgram: anchor.web3.SystemProgram.programId,
+      vault: vaultUsdc,
+      cranker: admin.publicKey
+    }).rpc({ commitment: "confirmed" });
+
+    // Indexers get the whole result from the EpochResolved event
+    const resolved = (await eventsOf(signature)).find((e) => e.name === "EpochResolved");
+    assert.equal(resolved.data.factionTvls.length, 3);
+    assert.equal(resolved.data.yieldPool.toString(), "50000000");
 
     const state = await program.account.gameState.fetch(gameStatePda);
     // Check Vanguard Score > 0
     const vScore = state.factions[0].score.toNumber();
     assert.isAbove(vScore, 0, "Vanguard should have positive score");
-  });
 
-  it("Injects Mock Yield", async () => {
-    const yieldAmount = new anchor.BN(50_000_000); // 50 USDC Yield
-    
-    // Admin injects yield to vault (simulating DeFi protocol return)
-    // Need to mint to admin first or use payer
-    const adminUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, admin.publicKey)).address;
-    await mintTo(provider.connection, (admin as any).payer, usdcMint, adminUsdc, admin.publicKey, BigInt(yieldAmount.toString()));
-
-    await program.methods.injectYield(yieldAmount).accounts({
-      vault: vaultUsdc,
-      providerUsdc: adminUsdc,
-      provider: admin.publicKey,
-      tokenProgram: TOKEN_PROGRAM_ID
-    }).rpc();
+    // Vault holds 550 against 500 of principal -> 50 USDC pool, all of it to the only winner.
+    assert.equal(state.epochYieldPool.toString(), "50000000");
+    assert.equal(state.factions[0].yieldAllocation.toString(), "50000000");
+    assert.equal(state.factions[1].yieldAllocation.toString(), "0");
   });
 
   it("Executes x402 Settlement (Player 1 Buys Sword)", async () => {
-    // Player 1 Won. They have 100 deposited.
-    // Let's say their share of yield is 20 USDC.
-    const userYield = new anchor.BN(20_000_000); 
+    // Player 1 Won. They hold all 100 of Vanguard's TVL, so the program
+    // pays them Vanguard's whole 50 USDC allocation.
 
     // Check inventory before
     let p1 = await program.account.userPosition.fetch(player1Pda);
-    assert.equal(p1.inventory.swordCount.toNumber(), 0);
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 0);
 
-    await program.methods.executeSettlement(userYield).accounts({
+    await program.methods.executeSettlement().accounts({
       userPosition: player1Pda,
       gameState: gameStatePda,
+      epochRecord: await currentEpochRecord(),
+      receipt: await currentReceipt(player1.publicKey),
       vault: vaultUsdc,
       userUsdc: player1Usdc,
       shopTreasury: shopTreasuryUsdc,
-      tokenProgram: TOKEN_PROGRAM_ID
-    }).rpc();
+      gameConfig: gameConfigPda,
+      itemAuthority: itemAuthorityPda,
+      payer: admin.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).remainingAccounts(settlementAccounts(player1.publicKey)).rpc();
 
     // Check inventory after
     p1 = await program.account.userPosition.fetch(player1Pda);
     
     // Should have bought 1 Sword (Cost 10 USDC)
-    // Yield (20) >= Threshold (10). Price (10).
-    // Remaining (10) -> AutoCompound.
-    assert.equal(p1.inventory.swordCount.toNumber(), 1, "Should have bought 1 sword");
+    // Yield (50) >= Threshold (10). Price (10).
+    // Remaining (40) -> AutoCompound.
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 1, "Should have bought 1 sword");
     
-    // Deposit should increase by remaining 10 USDC
-    // Initial: 100_000_000. + 10_000_000 = 110_000_000
-    assert.equal(p1.depositedAmount.toString(), "110000000", "Deposit should include remaining yield");
+    // Deposit should increase by remaining 40 USDC
+    // Initial: 100_000_000. + 40_000_000 = 140_000_000
+    assert.equal(p1.depositedAmount.toString(), "140000000", "Deposit should include remaining yield");
     
-    // Shop should have received 10 USDC
+    // Shop should have received 10 USDC (on top of Player 2's 4 USDC of Shields)
     const shopBal = await getAccount(provider.connection, shopTreasuryUsdc);
-    assert.equal(shopBal.amount.toString(), "10000000");
+    assert.equal(shopBal.amount.toString(), "14000000");
+
+    // The receipt keeps the breakdown after the logs are gone.
+    const receipt = await program.account.settlementReceipt.fetch(receiptPda(player1.publicKey, new anchor.BN(1)));
+    assert.equal(receipt.grossYield.toString(), "50000000");
+    assert.equal(receipt.swordApplied, false, "No Sword held before this settlement");
+    assert.deepEqual([...receipt.itemsBought], [SWORD_ID]);
+    assert.equal(receipt.compounded.toString(), "40000000");
+    assert.equal(receipt.sentToWallet.toString(), "0");
+  });
+
+  it("Rejects a Second Settlement in the Same Epoch", async () => {
+    try {
+      await program.methods.executeSettlement().accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        receipt: await currentReceipt(player1.publicKey),
+        vault: vaultUsdc,
+        userUsdc: player1Usdc,
+        shopTreasury: shopTreasuryUsdc,
+        gameConfig: gameConfigPda,
+        itemAuthority: itemAuthorityPda,
+        payer: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).remainingAccounts(settlementAccounts(player1.publicKey)).rpc();
+      assert.fail("Second settlement should have failed");
+    } catch (e) {
+      assert.include(e.toString(), "AlreadySettled");
+    }
+
+    const p1 = await program.account.userPosition.fetch(player1Pda);
+    assert.equal(p1.lastSettledEpoch.toNumber(), 1);
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 1, "Inventory must not change");
+  });
+
+  it("Blocks Deposits During Settlement", async () => {
+    try {
+      await program.methods.deposit(new anchor.BN(1_000_000)).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        vault: vaultUsdc,
+        userUsdc: player2Usdc,
+        user: player2.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player2]).rpc();
+      assert.fail("Deposit should fail during Settlement");
+    } catch (e) {
+      assert.include(e.toString(), "InvalidGameStatus");
+    }
+  });
+
+  it("Pauses and Unpauses the Game", async () => {
+    await program.methods.pauseGame().accounts({
+      gameState: gameStatePda,
+      pauser: admin.publicKey
+    }).rpc();
+
+    let state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.status.paused !== undefined, true);
+
+    try {
+      await program.methods.executeSettlement().accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        receipt: await currentReceipt(player2.publicKey),
+        vault: vaultUsdc,
+        userUsdc: player2Usdc,
+        shopTreasury: shopTreasuryUsdc,
+        gameConfig: gameConfigPda,
+        itemAuthority: itemAuthorityPda,
+        payer: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).remainingAccounts(settlementAccounts(player2.publicKey)).rpc();
+      assert.fail("Settlement should fail while paused");
+    } catch (e) {
+      assert.include(e.toString(), "GamePaused");
+    }
+
+    // Nobody joins a paused game either.
+    try {
+      await program.methods.registerUser(0).accounts({
+        userPosition: anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("user"), admin.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        gameState: gameStatePda,
+        user: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).rpc();
+      assert.fail("Registration should fail while paused");
+    } catch (e) {
+      assert.include(e.toString(), "GamePaused");
+    }
+
+    await program.methods.unpauseGame().accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey
+    }).rpc();
+
+    // Resumes the phase it was paused from
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.status.settlement !== undefined, true);
+  });
+
+  it("Settles the Losing Factions", async () => {
+    // start_new_epoch waits until every registered player has been settled.
+    try {
+      await program.methods.startNewEpoch().accounts({
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+      }).rpc();
+      assert.fail("New epoch should wait for pending settlements");
+    } catch (e) {
+      assert.include(e.toString(), "SettlementIncomplete");
+    }
+
+    // Mage (P2) lost but burns a Shield for the 2 USDC insurance payout (auto-compounded).
+    // Assassin (P3) lost with no Shield: nothing is paid out.
+    for (const [owner, pda, usdc] of [[player2.publicKey, player2Pda, player2Usdc], [player3.publicKey, player3Pda, player3Usdc]]) {
+      await program.methods.executeSettlement().accounts({
+        userPosition: pda,
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        receipt: await currentReceipt(owner),
+        vault: vaultUsdc,
+        userUsdc: usdc,
+        shopTreasury: shopTreasuryUsdc,
+        gameConfig: gameConfigPda,
+        itemAuthority: itemAuthorityPda,
+        payer: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).remainingAccounts(settlementAccounts(owner)).rpc();
+    }
+
+    const state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.settledCount.toNumber(), 3);
+
+    // Epoch 1 stays on record after the next epoch wipes the scores.
+    const record = await program.account.epochRecord.fetch(epochRecordPda(new anchor.BN(1)));
+    assert.equal(record.settledUsers.toNumber(), 3);
+    assert.equal(record.yieldPool.toString(), "50000000");
+    assert.isAbove(record.scores[0].toNumber(), 0, "Vanguard won Epoch 1");
+
+    const p2 = await program.account.userPosition.fetch(player2Pda);
+    assert.equal(await itemCount(player2.publicKey, SHIELD_ID), 1, "One Shield burned");
+    assert.equal(p2.depositedAmount.toString(), "102000000");
+
+    const receipt = await program.account.settlementReceipt.fetch(receiptPda(player2.publicKey, new anchor.BN(1)));
+    assert.equal(receipt.shieldApplied, true);
+    assert.equal(receipt.compounded.toString(), "2000000");
   });
 
   it("Starts New Epoch", async () => {
+    // Epoch end was pulled back by the force-close, so any caller can roll over.
     await program.methods.startNewEpoch().accounts({
       gameState: gameStatePda,
-      admin: admin.publicKey
+      gameConfig: gameConfigPda,
     }).rpc();
 
     const state = await program.account.gameState.fetch(gameStatePda);
     assert.equal(state.epochNumber.toNumber(), 2);
     assert.equal(state.factions[0].score.toNumber(), 0, "Scores should reset");
   });
+
+  it("Closes Old Settlement Receipts", async () => {
+    // The owner closes the Epoch 1 receipt, the crank that paid for it gets the rent back.
+    const receipt = receiptPda(player3.publicKey, new anchor.BN(1));
+    const before = await provider.connection.getBalance(admin.publicKey);
+    await program.methods.closeReceipt().accounts({
+      receipt,
+      gameState: gameStatePda,
+      payer: admin.publicKey,
+      authority: player3.publicKey,
+    }).signers([player3]).rpc();
+
+    assert.isNull(await program.account.settlementReceipt.fetchNullable(receipt));
+    assert.isAbove(await provider.connection.getBalance(admin.publicKey), before);
+  });
   
   it("Demonstrates Sword Boost in Next Settlement", async () => {
     // Simulating next win for Player 1
     // They now have a Sword.
-    // Yield = 20 USDC.
+    // Yield = 20 USDC (fresh injection below).
     // Boost logic: +20% = 24 USDC.
     
     // Need to set faction score > 0 manually or play again.
@@ -365,38 +810,913 @@ describe("ZOL Game & x402 Engine Tests", () => {
     
     // Force win state again hackily by resolving?
     // The TVL is still skewed! (Sticky TVL).
-    // P1: 110 (Compounded)
-    // P2: 100
+    // P1: 140 (Compounded)
+    // P2: 102 (Shield payout compounded)
     // P3: 300
-    // Total: 510
+    // Total: 542
     
-    // V Score = (300/510) - (100/510) = Positive.
+    // V Score = (300/542) - (102/542) = Positive.
+
+    // Epoch 2 yield: the previous pool was fully paid out and the 2 USDC Shield payout
+    // was moved in from the shop treasury, so 20 USDC makes a 20 USDC pool.
+    const adminUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, admin.publicKey)).address;
+    await mintTo(provider.connection, (admin as any).payer, usdcMint, adminUsdc, admin.publicKey, BigInt(20_000_000));
+    await program.methods.injectYield(new anchor.BN(20_000_000)).accounts({
+      gameState: gameStatePda,
+      vault: vaultUsdc,
+      providerUsdc: adminUsdc,
+      provider: admin.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID
+    }).rpc();
     
-    await program.methods.resolveEpoch().accounts({
+    // TVL has been flat since the epoch started, so the average equals the balance.
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    await program.methods.forceCloseEpoch().accounts({
         gameState: gameStatePda,
-        admin: admin.publicKey
+        epochRecord: await currentEpochRecord(),
+        systemProgram: anchor.web3.SystemProgram.programId,
+        vault: vaultUsdc,
+        cranker: admin.publicKey
     }).rpc();
     
-    const userYield = new anchor.BN(20_000_000);
+    // Check deposit before (140)
     
-    // Check deposit before (110)
-    
-    await program.methods.executeSettlement(userYield).accounts({
+    await program.methods.executeSettlement().accounts({
       userPosition: player1Pda,
       gameState: gameStatePda,
+      epochRecord: await currentEpochRecord(),
+      receipt: await currentReceipt(player1.publicKey),
       vault: vaultUsdc,
       userUsdc: player1Usdc,
       shopTreasury: shopTreasuryUsdc,
-      tokenProgram: TOKEN_PROGRAM_ID
-    }).rpc();
+      gameConfig: gameConfigPda,
+      itemAuthority: itemAuthorityPda,
+      payer: admin.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).remainingAccounts(settlementAccounts(player1.publicKey)).rpc();
     
     const p1 = await program.account.userPosition.fetch(player1Pda);
-    // Sword Logic: Yield 20 -> Boosted to 24.
+    // Sword Logic: Yield 20 -> Boosted to 24, the 4 USDC bonus comes from the shop treasury.
+    // The Sword is burned for it.
     // Automation: > 10? Buy another sword.
     // Cost 10. Remaining 14.
-    // Compounded: 110 + 14 = 124.
+    // Compounded: 140 + 14 = 154.
     
-    assert.equal(p1.inventory.swordCount.toNumber(), 2, "Should have bought 2nd sword");
-    assert.equal(p1.depositedAmount.toString(), "124000000", "Should include boosted yield");
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 1, "Should have burned the Sword and bought a new one");
+    assert.equal(p1.depositedAmount.toString(), "154000000", "Should include boosted yield");
+
+    // The bonus was backed by tokens: the vault still covers all principal.
+    const state = await program.account.gameState.fetch(gameStatePda);
+    const vaultBalance = await getAccount(provider.connection, vaultUsdc);
+    assert.isTrue(new anchor.BN(vaultBalance.amount.toString()).gte(state.totalTvl), "Vault must cover principal");
+    assert.equal(state.epochYieldPaid.toString(), "20000000");
+  });
+
+  it("Switches Faction With Cooldown", async () => {
+    // Still in Epoch 2 Settlement: switching is blocked.
+    try {
+      await program.methods.switchFaction(2).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        user: player2.publicKey,
+      }).signers([player2]).rpc();
+      assert.fail("Switch should fail during Settlement");
+    } catch (e) {
+      assert.include(e.toString(), "InvalidGameStatus");
+    }
+
+    // Finish Epoch 2 and roll over.
+    for (const [owner, pda, usdc] of [[player2.publicKey, player2Pda, player2Usdc], [player3.publicKey, player3Pda, player3Usdc]]) {
+      await program.methods.executeSettlement().accounts({
+        userPosition: pda,
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        receipt: await currentReceipt(owner),
+        vault: vaultUsdc,
+        userUsdc: usdc,
+        shopTreasury: shopTreasuryUsdc,
+        gameConfig: gameConfigPda,
+        itemAuthority: itemAuthorityPda,
+        payer: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).remainingAccounts(settlementAccounts(owner)).rpc();
+    }
+    await program.methods.startNewEpoch().accounts({ gameState: gameStatePda, gameConfig: gameConfigPda }).rpc();
+
+    // Mage (P2) -> Assassin
+    await program.methods.switchFaction(2).accounts({
+      userPosition: player2Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      user: player2.publicKey,
+    }).signers([player2]).rpc();
+
+    const p2 = await program.account.userPosition.fetch(player2Pda);
+    assert.equal(p2.factionId, 2);
+    assert.equal(p2.yieldForfeitedEpoch.toNumber(), 3, "Switching forfeits this epoch's yield");
+
+    const state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.factions[1].tvl.toString(), "0");
+    // P3's 300 plus P2's 104 (two Shield payouts compounded)
+    assert.equal(state.factions[2].tvl.toString(), "404000000");
+
+    // Default cooldown is 1 epoch: no second switch this epoch.
+    try {
+      await program.methods.switchFaction(1).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        user: player2.publicKey,
+      }).signers([player2]).rpc();
+      assert.fail("Second switch should be on cooldown");
+    } catch (e) {
+      assert.include(e.toString(), "SwitchCooldownActive");
+    }
+  });
+
+  it("Uses a Spyglass for Live Intel", async () => {
+    const [intelPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("intel"), player3.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods.buyItem(new anchor.BN(1)).accounts({
+      userPosition: player3Pda,
+      gameState: gameStatePda,
+      itemDefinition: itemPda(SPYGLASS_ID),
+      itemMint: itemMintPda(SPYGLASS_ID),
+      userItemAccount: itemAta(player3.publicKey, SPYGLASS_ID),
+      itemAuthority: itemAuthorityPda,
+      userUsdc: player3Usdc,
+      shopTreasury: shopTreasuryUsdc,
+      user: player3.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player3]).rpc();
+
+    await program.methods.useSpyglass().accounts({
+      userPosition: player3Pda,
+      gameState: gameStatePda,
+      itemDefinition: itemPda(SPYGLASS_ID),
+      itemMint: itemMintPda(SPYGLASS_ID),
+      userItemAccount: itemAta(player3.publicKey, SPYGLASS_ID),
+      intelReport: intelPda,
+      user: player3.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player3]).rpc();
+
+    assert.equal(await itemCount(player3.publicKey, SPYGLASS_ID), 0, "Spyglass is consumed");
+
+    // The public view is still frozen at the epoch start, before P2 switched to Assassin...
+    const state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.factions[1].visibleTvl.toString(), "104000000");
+
+    // ...but the report sees the live balances.
+    const report = await program.account.intelReport.fetch(intelPda);
+    assert.equal(report.epochNumber.toNumber(), 3);
+    assert.equal(report.factionTvls[1].toString(), "0");
+    assert.equal(report.factionTvls[2].toString(), "404000000");
+    assert.equal(report.projectedScores.length, 3);
+  });
+
+  it("Burns the Sword So Winners Can't Share It", async () => {
+    // A second Vanguard player joins, so Epoch 3 has two winners.
+    const player5 = anchor.web3.Keypair.generate();
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: admin.publicKey,
+        toPubkey: player5.publicKey,
+        lamports: 0.1 * anchor.web3.LAMPORTS_PER_SOL,
+      })
+    ));
+    const [player5Pda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user"), player5.publicKey.toBuffer()],
+      program.programId
+    );
+    const player5Usdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, player5.publicKey)).address;
+    await mintTo(provider.connection, (admin as any).payer, usdcMint, player5Usdc, admin.publicKey, BigInt(INITIAL_DEPOSIT.toString()));
+    await program.methods.registerUser(0).accounts({
+      userPosition: player5Pda,
+      gameState: gameStatePda,
+      user: player5.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player5]).rpc();
+    await program.methods.deposit(INITIAL_DEPOSIT).accounts({
+      userPosition: player5Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      vault: vaultUsdc,
+      userUsdc: player5Usdc,
+      user: player5.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player5]).rpc();
+    const player5Swords = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, itemMintPda(SWORD_ID), player5.publicKey)).address;
+
+    // 5 USDC keeps P1's boosted yield under the 10 USDC rule, so no new Sword is bought.
+    const adminUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, (admin as any).payer, usdcMint, admin.publicKey)).address;
+    await mintTo(provider.connection, (admin as any).payer, usdcMint, adminUsdc, admin.publicKey, BigInt(5_000_000));
+    await program.methods.injectYield(new anchor.BN(5_000_000)).accounts({
+      gameState: gameStatePda,
+      vault: vaultUsdc,
+      providerUsdc: adminUsdc,
+      provider: admin.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID
+    }).rpc();
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    await program.methods.forceCloseEpoch().accounts({
+      gameState: gameStatePda,
+      epochRecord: await currentEpochRecord(),
+      systemProgram: anchor.web3.SystemProgram.programId,
+      vault: vaultUsdc,
+      cranker: admin.publicKey
+    }).rpc();
+
+    const settle = async (owner: anchor.web3.PublicKey, pda: anchor.web3.PublicKey, usdc: anchor.web3.PublicKey) => {
+      const receipt = await currentReceipt(owner);
+      await program.methods.executeSettlement().accounts({
+        userPosition: pda,
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        receipt,
+        vault: vaultUsdc,
+        userUsdc: usdc,
+        shopTreasury: shopTreasuryUsdc,
+        gameConfig: gameConfigPda,
+        itemAuthority: itemAuthorityPda,
+        payer: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).remainingAccounts(settlementAccounts(owner)).rpc();
+      return program.account.settlementReceipt.fetch(receipt);
+    };
+
+    // P1 wins with their Sword, which is burned for the bonus...
+    const p1Receipt = await settle(player1.publicKey, player1Pda, player1Usdc);
+    assert.isTrue(p1Receipt.swordApplied);
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 0, "The Sword is consumed");
+
+    // ...so there is nothing left to hand to P5 before they settle.
+    try {
+      await transfer(provider.connection, (admin as any).payer, itemAta(player1.publicKey, SWORD_ID), player5Swords, player1, 1);
+      assert.fail("A consumed Sword can't be passed on");
+    } catch (e) {
+      assert.include(e.toString(), "custom program error: 0x1"); // SPL Token InsufficientFunds
+    }
+
+    const p5Receipt = await settle(player5.publicKey, player5Pda, player5Usdc);
+    assert.isAbove(p5Receipt.grossYield.toNumber(), 0, "P5 won too");
+    assert.isFalse(p5Receipt.swordApplied, "Only one winner gets the Sword bonus");
+
+    // Losers settle as well, closing out Epoch 3.
+    for (const [owner, pda, usdc] of [[player2.publicKey, player2Pda, player2Usdc], [player3.publicKey, player3Pda, player3Usdc]]) {
+      await settle(owner, pda, usdc);
+    }
+  });
+
+  it("Commits and Reveals a Hidden Deposit", async () => {
+    // The emergency timeout has to outlast the grace period plus a day.
+    try {
+      await program.methods.updateConfig({
+        epochDuration: new anchor.BN(20),
+        settlementGracePeriod: new anchor.BN(86400),
+        switchCooldownEpochs: new anchor.BN(1),
+        switchForfeitsYield: true,
+        commitRevealEnabled: true,
+        revealWindow: new anchor.BN(10),
+        marketplaceFeeBps: new anchor.BN(250),
+        emergencyTimeout: new anchor.BN(86400),
+      }).accounts({
+        gameConfig: gameConfigPda,
+        gameState: gameStatePda,
+        configAdmin: admin.publicKey,
+      }).rpc();
+      assert.fail("A timeout inside the grace period should be rejected");
+    } catch (e) {
+      assert.include(e.toString(), "InvalidConfig");
+    }
+
+    // Short epochs with a 10s reveal window, commit-reveal on. A 2 epoch switch cooldown
+    // keeps P2, who switched in Epoch 3, locked in during Epoch 4.
+    await program.methods.updateConfig({
+      epochDuration: new anchor.BN(20),
+      settlementGracePeriod: new anchor.BN(86400),
+      switchCooldownEpochs: new anchor.BN(2),
+      switchForfeitsYield: true,
+      commitRevealEnabled: true,
+      revealWindow: new anchor.BN(10),
+      marketplaceFeeBps: new anchor.BN(250),
+      emergencyTimeout: new anchor.BN(604800),
+    }).accounts({
+      gameConfig: gameConfigPda,
+      gameState: gameStatePda,
+      configAdmin: admin.publicKey,
+    }).rpc();
+
+    // Epoch 3 was settled above, so the new duration applies to Epoch 4.
+    await program.methods.startNewEpoch().accounts({ gameState: gameStatePda, gameConfig: gameConfigPda }).rpc();
+
+    // Plain deposits would leak the allocation.
+    try {
+      await program.methods.deposit(new anchor.BN(1_000_000)).accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        vault: vaultUsdc,
+        userUsdc: player1Usdc,
+        user: player1.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player1]).rpc();
+      assert.fail("Direct deposit should be disabled");
+    } catch (e) {
+      assert.include(e.toString(), "DirectDepositDisabled");
+    }
+
+    const state = await program.account.gameState.fetch(gameStatePda);
+    const [commitmentPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("commitment"), player1.publicKey.toBuffer(), state.epochNumber.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+
+    // P1 hides 5 USDC for Vanguard behind a 10 USDC escrow.
+    const amount = new anchor.BN(5_000_000);
+    const salt = anchor.web3.Keypair.generate().publicKey.toBuffer();
+    const commitment = createHash("sha256")
+      .update(Buffer.concat([Buffer.from([0]), amount.toArrayLike(Buffer, "le", 8), salt]))
+      .digest();
+    const vanguardBefore = state.factions[0].tvl;
+
+    await program.methods.commitDeposit([...commitment], new anchor.BN(10_000_000)).accounts({
+      userPosition: player1Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      depositCommitment: commitmentPda,
+      vault: vaultUsdc,
+      userUsdc: player1Usdc,
+      user: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player1]).rpc();
+
+    // P2 empties their Assassin position and hides a deposit for Vanguard, hoping the reveal
+    // moves them over without the switch cooldown.
+    const p2Position = await program.account.userPosition.fetch(player2Pda);
+    await program.methods.withdraw(p2Position.depositedAmount).accounts({
+      userPosition: player2Pda,
+      gameState: gameStatePda,
+      vault: vaultUsdc,
+      userUsdc: player2Usdc,
+      user: player2.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player2]).rpc();
+    const [p2CommitmentPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("commitment"), player2.publicKey.toBuffer(), state.epochNumber.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const p2Salt = anchor.web3.Keypair.generate().publicKey.toBuffer();
+    const p2Commitment = createHash("sha256")
+      .update(Buffer.concat([Buffer.from([0]), amount.toArrayLike(Buffer, "le", 8), p2Salt]))
+      .digest();
+    await program.methods.commitDeposit([...p2Commitment], amount).accounts({
+      userPosition: player2Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      depositCommitment: p2CommitmentPda,
+      vault: vaultUsdc,
+      userUsdc: player2Usdc,
+      user: player2.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player2]).rpc();
+
+    const revealAccounts = {
+      userPosition: player1Pda,
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      depositCommitment: commitmentPda,
+      vault: vaultUsdc,
+      userUsdc: player1Usdc,
+      user: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // Too early: reveal window opens 10s before the end.
+    try {
+      await program.methods.revealDeposit(0, amount, [...salt]).accounts(revealAccounts).signers([player1]).rpc();
+      assert.fail("Reveal should wait for the window");
+    } catch (e) {
+      assert.include(e.toString(), "RevealWindowNotOpen");
+    }
+
+    await new Promise((resolve) => setTimeout(resolve, 11000));
+
+    // Changing faction on reveal is a switch, and P2 is still on cooldown.
+    try {
+      await program.methods.revealDeposit(0, amount, [...p2Salt]).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        depositCommitment: p2CommitmentPda,
+        vault: vaultUsdc,
+        userUsdc: player2Usdc,
+        user: player2.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player2]).rpc();
+      assert.fail("Reveal into another faction should respect the switch cooldown");
+    } catch (e) {
+      assert.include(e.toString(), "SwitchCooldownActive");
+    }
+    assert.equal((await program.account.userPosition.fetch(player2Pda)).factionId, 2);
+
+    const usdcBefore = await getAccount(provider.connection, player1Usdc);
+    const p1Before = await program.account.userPosition.fetch(player1Pda);
+    await program.methods.revealDeposit(0, amount, [...salt]).accounts(revealAccounts).signers([player1]).rpc();
+
+    // The revealed 5 USDC only counts from the reveal on, not from the commit: P1's weight so
+    // far is just their old principal since epoch start.
+    const p1After = await program.account.userPosition.fetch(player1Pda);
+    const elapsed = p1After.lastAccrualTs.sub(state.epochStartTs);
+    assert.equal(p1After.tvlTimeWeighted.toString(), p1Before.depositedAmount.mul(elapsed).toString());
+    assert.equal(p1After.depositedAmount.sub(p1Before.depositedAmount).toString(), amount.toString());
+
+    const after = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(after.factions[0].tvl.sub(vanguardBefore).toString(), amount.toString());
+    assert.equal(after.totalCommitted.toString(), amount.toString(), "Only P2's unrevealed escrow is left");
+    const usdcAfter = await getAccount(provider.connection, player1Usdc);
+    assert.equal((usdcAfter.amount - usdcBefore.amount).toString(), "5000000", "Unused escrow is refunded");
+    assert.isNull(await program.account.depositCommitment.fetchNullable(commitmentPda));
+
+    // P2 gets the unrevealed escrow back once the epoch is over.
+    const waitMs = (after.epochEndTs.toNumber() + 2) * 1000 - Date.now();
+    if (waitMs > 0) {
+      await new Promise((resolve) => setTimeout(resolve, waitMs));
+    }
+    await program.methods.refundCommitment().accounts({
+      gameState: gameStatePda,
+      depositCommitment: p2CommitmentPda,
+      vault: vaultUsdc,
+      userUsdc: player2Usdc,
+      user: player2.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player2]).rpc();
+    assert.equal((await program.account.gameState.fetch(gameStatePda)).totalCommitted.toString(), "0");
+
+    // Back to plain deposits for the following epochs.
+    await program.methods.updateConfig({
+      epochDuration: new anchor.BN(20),
+      settlementGracePeriod: new anchor.BN(86400),
+      switchCooldownEpochs: new anchor.BN(1),
+      switchForfeitsYield: true,
+      commitRevealEnabled: false,
+      revealWindow: new anchor.BN(10),
+      marketplaceFeeBps: new anchor.BN(250),
+      emergencyTimeout: new anchor.BN(604800),
+    }).accounts({
+      gameConfig: gameConfigPda,
+      gameState: gameStatePda,
+      configAdmin: admin.publicKey,
+    }).rpc();
+  });
+
+  it("Trades Items on the Marketplace", async () => {
+    const [listingPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("listing"), player1.publicKey.toBuffer(), Buffer.from([SWORD_ID])],
+      program.programId
+    );
+    const [escrowPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("listing_escrow"), listingPda.toBuffer()],
+      program.programId
+    );
+    const listAccounts = {
+      gameState: gameStatePda,
+      itemDefinition: itemPda(SWORD_ID),
+      itemMint: itemMintPda(SWORD_ID),
+      listing: listingPda,
+      escrow: escrowPda,
+      sellerItemAccount: itemAta(player1.publicKey, SWORD_ID),
+      seller: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+
+    // P1's last Sword was burned in Epoch 3, so they restock 2 from the shop.
+    await program.methods.buyItem(new anchor.BN(2)).accounts({
+      userPosition: player1Pda,
+      gameState: gameStatePda,
+      itemDefinition: itemPda(SWORD_ID),
+      itemMint: itemMintPda(SWORD_ID),
+      userItemAccount: itemAta(player1.publicKey, SWORD_ID),
+      itemAuthority: itemAuthorityPda,
+      userUsdc: player1Usdc,
+      shopTreasury: shopTreasuryUsdc,
+      user: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player1]).rpc();
+
+    // P1 sells one of their 2 Swords for 12 USDC.
+    await program.methods.listItem(new anchor.BN(1), new anchor.BN(12_000_000))
+      .accounts(listAccounts).signers([player1]).rpc();
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 1);
+    const escrow = await getAccount(provider.connection, escrowPda);
+    assert.equal(escrow.amount.toString(), "1");
+
+    const sellerBefore = await getAccount(provider.connection, player1Usdc);
+    const shopBefore = await getAccount(provider.connection, shopTreasuryUsdc);
+
+    const fillAccounts = {
+      gameState: gameStatePda,
+      gameConfig: gameConfigPda,
+      listing: listingPda,
+      itemDefinition: itemPda(SWORD_ID),
+      itemMint: itemMintPda(SWORD_ID),
+      escrow: escrowPda,
+      buyerItemAccount: itemAta(player3.publicKey, SWORD_ID),
+      itemAuthority: itemAuthorityPda,
+      buyerUsdc: player3Usdc,
+      sellerUsdc: player1Usdc,
+      shopTreasury: shopTreasuryUsdc,
+      seller: player1.publicKey,
+      buyer: player3.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+    await program.methods.fillListing(new anchor.BN(1), new anchor.BN(12_000_000))
+      .accounts(fillAccounts).signers([player3]).rpc();
+
+    // 2.5% fee: 0.3 USDC to the shop, 11.7 USDC to the seller.
+    assert.equal(await itemCount(player3.publicKey, SWORD_ID), 1);
+    const sellerAfter = await getAccount(provider.connection, player1Usdc);
+    const shopAfter = await getAccount(provider.connection, shopTreasuryUsdc);
+    assert.equal((sellerAfter.amount - sellerBefore.amount).toString(), "11700000");
+    assert.equal((shopAfter.amount - shopBefore.amount).toString(), "300000");
+    // Sold out: listing and escrow are closed.
+    assert.isNull(await program.account.listing.fetchNullable(listingPda));
+
+    // Listing the last Sword and cancelling returns it.
+    await program.methods.listItem(new anchor.BN(1), new anchor.BN(50_000_000))
+      .accounts(listAccounts).signers([player1]).rpc();
+
+    // A buyer who saw the 12 USDC price is not charged the relisted 50.
+    try {
+      await program.methods.fillListing(new anchor.BN(1), new anchor.BN(12_000_000))
+        .accounts(fillAccounts).signers([player3]).rpc();
+      assert.fail("Fill above the buyer's price limit should fail");
+    } catch (e) {
+      assert.include(e.toString(), "PriceAboveLimit");
+    }
+
+    await program.methods.cancelListing().accounts({
+      listing: listingPda,
+      escrow: escrowPda,
+      sellerItemAccount: itemAta(player1.publicKey, SWORD_ID),
+      seller: player1.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player1]).rpc();
+    assert.equal(await itemCount(player1.publicKey, SWORD_ID), 1);
+    assert.isNull(await program.account.listing.fetchNullable(listingPda));
+  });
+
+  it("Transfers Admin in Two Steps", async () => {
+    const newAdmin = anchor.web3.Keypair.generate();
+
+    // A pending transfer can be cancelled before it is accepted.
+    await program.methods.proposeAdmin(player2.publicKey).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+    await program.methods.cancelAdminTransfer().accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+    try {
+      await program.methods.acceptAdmin().accounts({
+        gameState: gameStatePda,
+        newAdmin: player2.publicKey,
+      }).signers([player2]).rpc();
+      assert.fail("Cancelled transfer should not be acceptable");
+    } catch (e) {
+      assert.include(e.toString(), "NotPendingAdmin");
+    }
+
+    await program.methods.proposeAdmin(newAdmin.publicKey).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+    let state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.admin.toBase58(), admin.publicKey.toBase58(), "Admin only changes on accept");
+
+    await program.methods.acceptAdmin().accounts({
+      gameState: gameStatePda,
+      newAdmin: newAdmin.publicKey,
+    }).signers([newAdmin]).rpc();
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.admin.toBase58(), newAdmin.publicKey.toBase58());
+    assert.isNull(state.pendingAdmin);
+
+    // Hand it back for the remaining tests.
+    await program.methods.proposeAdmin(admin.publicKey).accounts({
+      gameState: gameStatePda,
+      admin: newAdmin.publicKey,
+    }).signers([newAdmin]).rpc();
+    await program.methods.acceptAdmin().accounts({
+      gameState: gameStatePda,
+      newAdmin: admin.publicKey,
+    }).rpc();
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.admin.toBase58(), admin.publicKey.toBase58());
+  });
+
+  it("Grants and Revokes Roles", async () => {
+    // Without a role, P3 cannot pause.
+    try {
+      await program.methods.pauseGame().accounts({
+        gameState: gameStatePda,
+        pauser: player3.publicKey,
+      }).signers([player3]).rpc();
+      assert.fail("Pause without the role should fail");
+    } catch (e) {
+      assert.include(e.toString(), "Unauthorized");
+    }
+
+    await program.methods.grantRole({ pauser: {} }, player3.publicKey).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+    let state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal(state.roles.pauser.toBase58(), player3.publicKey.toBase58());
+
+    // The pauser can pause, but only the admin unpauses.
+    await program.methods.pauseGame().accounts({
+      gameState: gameStatePda,
+      pauser: player3.publicKey,
+    }).signers([player3]).rpc();
+    try {
+      await program.methods.unpauseGame().accounts({
+        gameState: gameStatePda,
+        admin: player3.publicKey,
+      }).signers([player3]).rpc();
+      assert.fail("Pauser should not be able to unpause");
+    } catch (e) {
+      assert.include(e.toString(), "ConstraintAddress");
+    }
+    await program.methods.unpauseGame().accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+
+    // The pauser role does not cover other privileged calls.
+    try {
+      await program.methods.forceCloseEpoch().accounts({
+        gameState: gameStatePda,
+        epochRecord: await currentEpochRecord(),
+        systemProgram: anchor.web3.SystemProgram.programId,
+        vault: vaultUsdc,
+        cranker: player3.publicKey,
+      }).signers([player3]).rpc();
+      assert.fail("Pauser should not be able to force-close");
+    } catch (e) {
+      assert.include(e.toString(), "Unauthorized");
+    }
+
+    await program.methods.revokeRole({ pauser: {} }).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.isNull(state.roles.pauser);
+  });
+
+  it("Closes an Empty Position and Registers Again", async () => {
+    // A funded position cannot be closed.
+    try {
+      await program.methods.closePosition().accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        user: player1.publicKey,
+      }).signers([player1]).rpc();
+      assert.fail("Closing a funded position should fail");
+    } catch (e) {
+      assert.include(e.toString(), "PositionNotEmpty");
+    }
+
+    const player4 = anchor.web3.Keypair.generate();
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: admin.publicKey,
+        toPubkey: player4.publicKey,
+        lamports: 0.1 * anchor.web3.LAMPORTS_PER_SOL,
+      })
+    ));
+    const [player4Pda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user"), player4.publicKey.toBuffer()],
+      program.programId
+    );
+    const register = (factionId: number) => program.methods.registerUser(factionId).accounts({
+      userPosition: player4Pda,
+      gameState: gameStatePda,
+      user: player4.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([player4]).rpc();
+    const close = () => program.methods.closePosition().accounts({
+      userPosition: player4Pda,
+      gameState: gameStatePda,
+      user: player4.publicKey,
+    }).signers([player4]).rpc();
+
+    const before = await program.account.gameState.fetch(gameStatePda);
+    await register(0);
+    await close();
+
+    let state = await program.account.gameState.fetch(gameStatePda);
+    assert.isNull(await program.account.userPosition.fetchNullable(player4Pda));
+    assert.equal(state.factions[0].playerCount.toString(), before.factions[0].playerCount.toString());
+
+    // Free to come back in another faction
+    await register(1);
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.equal((await program.account.userPosition.fetch(player4Pda)).factionId, 1);
+    assert.equal(state.factions[1].playerCount.toNumber(), before.factions[1].playerCount.toNumber() + 1);
+    await close();
+  });
+
+  it("Only Migrates Legacy Accounts", async () => {
+    // Accounts created by this version are already on the current layout.
+    try {
+      await program.methods.migrateGameState().accounts({
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        vault: vaultPda,
+        admin: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).rpc();
+      assert.fail("Current GameState should not migrate");
+    } catch (e) {
+      assert.include(e.toString(), "AlreadyMigrated");
+    }
+
+    try {
+      await program.methods.migrateUserPosition().accounts({
+        userPosition: player1Pda,
+        user: player1.publicKey,
+        gameState: gameStatePda,
+        payer: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).rpc();
+      assert.fail("Current UserPosition should not migrate");
+    } catch (e) {
+      assert.include(e.toString(), "AlreadyMigrated");
+    }
+    assert.equal((await program.account.userPosition.fetch(player1Pda)).version, 1);
+    // The config was left alone
+    assert.equal((await program.account.gameConfig.fetch(gameConfigPda)).marketplaceFeeBps.toNumber(), 250);
+
+    // init_vault already created the shop treasury; init_shop_treasury is for older deploys.
+    try {
+      await program.methods.initShopTreasury().accounts({
+        shopTreasury: shopTreasuryUsdc,
+        usdcMint,
+        gameState: gameStatePda,
+        admin: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      }).rpc();
+      assert.fail("Shop treasury should only be created once");
+    } catch (e) {
+      // The system program refuses to allocate it again; the reason is in the logs
+      assert.include(String(e.logs ?? e), "already in use");
+    }
+  });
+
+  it("Withdraws Shop Revenue as Treasurer", async () => {
+    const withdraw = (amount: number, treasurer: anchor.web3.Keypair, destination: anchor.web3.PublicKey) =>
+      program.methods.withdrawTreasury(new anchor.BN(amount)).accounts({
+        gameState: gameStatePda,
+        shopTreasury: shopTreasuryUsdc,
+        destination,
+        treasurer: treasurer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([treasurer]).rpc();
+
+    try {
+      await withdraw(1_000_000, player3, player3Usdc);
+      assert.fail("Withdraw without the role should fail");
+    } catch (e) {
+      assert.include(e.toString(), "Unauthorized");
+    }
+
+    await program.methods.grantRole({ treasurer: {} }, player3.publicKey).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+
+    const treasuryBefore = await getAccount(provider.connection, shopTreasuryUsdc);
+    const before = await getAccount(provider.connection, player3Usdc);
+    await withdraw(1_000_000, player3, player3Usdc);
+    const treasuryAfter = await getAccount(provider.connection, shopTreasuryUsdc);
+    const after = await getAccount(provider.connection, player3Usdc);
+    assert.equal((treasuryBefore.amount - treasuryAfter.amount).toString(), "1000000");
+    assert.equal((after.amount - before.amount).toString(), "1000000");
+
+    await program.methods.revokeRole({ treasurer: {} }).accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+  });
+
+  it("Rejects Deposits and Switches After Epoch End", async () => {
+    // The 20s epoch is still Active until someone resolves it, but its scores are fixed.
+    let state = await program.account.gameState.fetch(gameStatePda);
+    const waitMs = (state.epochEndTs.toNumber() + 2) * 1000 - Date.now();
+    if (waitMs > 0) {
+      await new Promise((resolve) => setTimeout(resolve, waitMs));
+    }
+    state = await program.account.gameState.fetch(gameStatePda);
+    assert.ok(state.status.active);
+
+    try {
+      await program.methods.deposit(new anchor.BN(1_000_000)).accounts({
+        userPosition: player1Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        vault: vaultUsdc,
+        userUsdc: player1Usdc,
+        user: player1.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player1]).rpc();
+      assert.fail("Deposit should fail after epoch end");
+    } catch (e) {
+      assert.include(e.toString(), "EpochEnded");
+    }
+
+    const position = await program.account.userPosition.fetch(player2Pda);
+    try {
+      await program.methods.switchFaction((position.factionId + 1) % 3).accounts({
+        userPosition: player2Pda,
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+        user: player2.publicKey,
+      }).signers([player2]).rpc();
+      assert.fail("Switch should fail after epoch end");
+    } catch (e) {
+      assert.include(e.toString(), "EpochEnded");
+    }
+  });
+
+  // Emergency mode is terminal, so this stays the last test.
+  it("Lets Players Exit With Principal in Emergency Mode", async () => {
+    // The epoch ended moments ago, far short of the 7 day timeout.
+    try {
+      await program.methods.triggerEmergency().accounts({
+        gameState: gameStatePda,
+        gameConfig: gameConfigPda,
+      }).rpc();
+      assert.fail("Emergency should not trigger before the timeout");
+    } catch (e) {
+      assert.include(e.toString(), "EmergencyTimeoutNotReached");
+    }
+
+    await program.methods.declareEmergency().accounts({
+      gameState: gameStatePda,
+      admin: admin.publicKey,
+    }).rpc();
+
+    try {
+      await program.methods.withdraw(new anchor.BN(1_000_000)).accounts({
+        userPosition: player3Pda,
+        gameState: gameStatePda,
+        vault: vaultUsdc,
+        userUsdc: player3Usdc,
+        user: player3.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      }).signers([player3]).rpc();
+      assert.fail("Regular withdraw should be blocked");
+    } catch (e) {
+      assert.include(e.toString(), "GameInEmergency");
+    }
+
+    const position = await program.account.userPosition.fetch(player3Pda);
+    const before = await getAccount(provider.connection, player3Usdc);
+    await program.methods.emergencyWithdraw().accounts({
+      userPosition: player3Pda,
+      gameState: gameStatePda,
+      vault: vaultUsdc,
+      userUsdc: player3Usdc,
+      user: player3.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    }).signers([player3]).rpc();
+
+    const after = await getAccount(provider.connection, player3Usdc);
+    assert.equal((after.amount - before.amount).toString(), position.depositedAmount.toString());
+    assert.isNull(await program.account.userPosition.fetchNullable(player3Pda));
   });
 });